@@ -0,0 +1,32 @@
+ALTER TABLE posts ADD COLUMN format text NOT NULL CHECK (format in ('plain', 'markdown')) DEFAULT 'plain';
+
+-- Sanitised HTML rendered from body at write time
+ALTER TABLE posts ADD COLUMN body_html text NOT NULL DEFAULT '';
+
+-- Existing posts are plain text, render them the way `render::plain` does:
+-- a paragraph per blank line separated block, escaped, with line breaks kept.
+-- Paragraphs are trimmed of what Rust counts as whitespace, Unicode's White_Space
+WITH RECURSIVE paragraphs(post_id, n, paragraph, rest) AS (
+    SELECT id, 0, NULL, replace(body, char(13) || char(10), char(10)) || char(10) || char(10)
+    FROM posts
+    WHERE body != ''
+    UNION ALL
+    SELECT post_id, n + 1,
+        trim(substr(rest, 1, instr(rest, char(10) || char(10)) - 1), char(9, 10, 11, 12, 13, 32, 133, 160, 5760, 8192, 8193, 8194, 8195, 8196, 8197, 8198, 8199, 8200, 8201, 8202, 8232, 8233, 8239, 8287, 12288)),
+        substr(rest, instr(rest, char(10) || char(10)) + 2)
+    FROM paragraphs
+    WHERE rest != ''
+)
+UPDATE posts SET body_html = coalesce((
+    SELECT group_concat(html, '')
+    FROM (
+        SELECT '<p>' || replace(
+            replace(replace(replace(replace(replace(paragraph, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '"', '&quot;'), '''', '&#39;'),
+            char(10), '<br/>'
+        ) || '</p>' AS html
+        FROM paragraphs
+        WHERE paragraphs.post_id = posts.id AND paragraph != ''
+        ORDER BY n
+    )
+), '')
+WHERE body != '';
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS media
+(
+    id INTEGER PRIMARY KEY NOT NULL,
+    post_id INTEGER NOT NULL,
+    user_id INTEGER,
+    storage_key text NOT NULL UNIQUE,
+    mime text NOT NULL,
+    size INTEGER NOT NULL,
+    alt text NOT NULL CHECK (alt != ''),
+    created text NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (post_id) REFERENCES posts (id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS media_post ON media (post_id);
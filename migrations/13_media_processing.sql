@@ -0,0 +1,20 @@
+ALTER TABLE media ADD COLUMN status text NOT NULL DEFAULT 'ready' CHECK (status IN ('pending', 'processing', 'ready', 'failed'));
+ALTER TABLE media ADD COLUMN width INTEGER;
+ALTER TABLE media ADD COLUMN height INTEGER;
+ALTER TABLE media ADD COLUMN blurhash text;
+
+CREATE TABLE IF NOT EXISTS media_variants
+(
+    id INTEGER PRIMARY KEY NOT NULL,
+    media_id INTEGER NOT NULL,
+    storage_key text NOT NULL UNIQUE,
+    mime text NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    FOREIGN KEY (media_id) REFERENCES media (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS media_variants_media ON media_variants (media_id);
+
+-- Images uploaded before processing existed still carry their metadata
+UPDATE media SET status = 'pending' WHERE mime LIKE 'image/%';
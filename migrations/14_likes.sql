@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS likes
+(
+    user_id INTEGER NOT NULL,
+    post_id INTEGER NOT NULL,
+    created text NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, post_id),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (post_id) REFERENCES posts (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS likes_post ON likes (post_id);
@@ -0,0 +1,15 @@
+ALTER TABLE posts ADD COLUMN reply_permission text NOT NULL DEFAULT 'everyone' CHECK (reply_permission IN ('everyone', 'followers', 'nobody'));
+
+CREATE TABLE IF NOT EXISTS replies
+(
+    id INTEGER PRIMARY KEY NOT NULL,
+    post_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    body text NOT NULL CHECK (body != ''),
+    body_html text NOT NULL,
+    created text NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (post_id) REFERENCES posts (id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS replies_post ON replies (post_id);
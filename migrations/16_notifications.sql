@@ -0,0 +1,23 @@
+CREATE TABLE IF NOT EXISTS notifications
+(
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL,
+    actor_id INTEGER NOT NULL,
+    kind text NOT NULL CHECK (kind IN ('follow', 'follow_request', 'like', 'reblog', 'reply', 'mention')),
+    post_id INTEGER,
+    is_read INTEGER NOT NULL DEFAULT 0,
+    created text NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (actor_id) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (post_id) REFERENCES posts (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS notifications_user ON notifications (user_id, is_read);
+
+CREATE TABLE IF NOT EXISTS notification_mutes
+(
+    user_id INTEGER NOT NULL,
+    kind text NOT NULL,
+    PRIMARY KEY (user_id, kind),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
@@ -0,0 +1 @@
+ALTER TABLE posts ADD COLUMN visibility text NOT NULL DEFAULT 'public' CHECK (visibility IN ('public', 'unlisted', 'followers', 'mentioned'));
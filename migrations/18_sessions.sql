@@ -0,0 +1,7 @@
+CREATE TABLE sessions (
+    id text PRIMARY KEY NOT NULL,
+    data text NOT NULL,
+    expiry_date integer NOT NULL
+);
+
+CREATE INDEX sessions_expiry ON sessions(expiry_date);
@@ -0,0 +1,7 @@
+ALTER TABLE sessions ADD COLUMN user_id integer REFERENCES users(id) ON DELETE CASCADE;
+ALTER TABLE sessions ADD COLUMN created text;
+ALTER TABLE sessions ADD COLUMN last_seen text;
+ALTER TABLE sessions ADD COLUMN user_agent text;
+ALTER TABLE sessions ADD COLUMN ip text;
+
+CREATE INDEX sessions_user ON sessions(user_id);
@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN email_verified integer NOT NULL DEFAULT 0 CHECK (email_verified IN (0, 1));
+
+-- Accounts from before verification existed keep being able to post
+UPDATE users SET email_verified = 1;
@@ -0,0 +1,13 @@
+-- The secret is kept while enrolling, a second step is only asked for once it's enabled
+ALTER TABLE users ADD COLUMN totp_secret text;
+ALTER TABLE users ADD COLUMN totp_enabled integer NOT NULL DEFAULT 0 CHECK (totp_enabled IN (0, 1));
+-- The last time step a code was accepted for, so the same code can't be used twice
+ALTER TABLE users ADD COLUMN totp_last_step integer;
+
+CREATE TABLE IF NOT EXISTS recovery_codes
+(
+    user_id INTEGER NOT NULL,
+    code_hash text NOT NULL,
+    PRIMARY KEY (user_id, code_hash),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS passkeys
+(
+    -- The credential id, base64url encoded
+    id text PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL,
+    name text NOT NULL,
+    -- SEC1 encoded P-256 key, base64url encoded
+    public_key text NOT NULL,
+    sign_count INTEGER NOT NULL DEFAULT 0,
+    created text NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used text,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS passkeys_user ON passkeys (user_id);
@@ -0,0 +1,4 @@
+-- Codes tried since the last one accepted, whichever session they came from
+ALTER TABLE users ADD COLUMN totp_failures integer NOT NULL DEFAULT 0;
+-- Unix time until which no more codes are checked, once too many were wrong
+ALTER TABLE users ADD COLUMN totp_locked_until integer;
@@ -0,0 +1,32 @@
+CREATE TABLE IF NOT EXISTS post_ancestry
+(
+    post_id INTEGER NOT NULL,
+    ancestor_id INTEGER NOT NULL,
+    depth INTEGER NOT NULL CHECK (depth > 0), -- 1 is the immediate parent
+    PRIMARY KEY (post_id, ancestor_id),
+    FOREIGN KEY (post_id) REFERENCES posts (id) ON DELETE CASCADE,
+    FOREIGN KEY (ancestor_id) REFERENCES posts (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS post_ancestry_ancestor ON post_ancestry (ancestor_id);
+
+-- Convert slash-separated thread strings (root first) into ancestry rows
+WITH RECURSIVE split (post_id, rest, ancestor_id, position) AS (
+    SELECT id, thread || '/', NULL, 0 FROM posts WHERE thread IS NOT NULL AND thread != ''
+    UNION ALL
+    SELECT
+        post_id,
+        substr(rest, instr(rest, '/') + 1),
+        CAST(substr(rest, 1, instr(rest, '/') - 1) AS INTEGER),
+        position + 1
+    FROM split WHERE rest != ''
+)
+INSERT OR IGNORE INTO post_ancestry (post_id, ancestor_id, depth)
+SELECT
+    split.post_id,
+    split.ancestor_id,
+    (SELECT max(s.position) FROM split s WHERE s.post_id = split.post_id) - split.position + 1
+FROM split
+WHERE split.ancestor_id IN (SELECT id FROM posts);
+
+ALTER TABLE posts DROP COLUMN thread;
@@ -0,0 +1,8 @@
+-- Merge duplicate tags onto the lowest id before enforcing uniqueness
+UPDATE OR IGNORE postTags
+SET tag_id = (SELECT min(t.id) FROM tags t WHERE t.tag = (SELECT tag FROM tags WHERE tags.id = postTags.tag_id))
+WHERE tag_id IN (SELECT id FROM tags);
+
+DELETE FROM tags WHERE tag IS NULL OR id NOT IN (SELECT min(id) FROM tags GROUP BY tag);
+
+CREATE UNIQUE INDEX IF NOT EXISTS tags_tag ON tags (tag);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS tagFollows
+(
+    user_id INTEGER NOT NULL,
+    tag_id INTEGER NOT NULL,
+    created text NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, tag_id),
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (tag_id) REFERENCES tags (id) ON DELETE CASCADE
+);
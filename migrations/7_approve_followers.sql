@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN approve_followers integer NOT NULL CHECK (approve_followers in (0, 1)) DEFAULT 0;
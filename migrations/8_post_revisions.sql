@@ -0,0 +1,15 @@
+ALTER TABLE posts ADD COLUMN edited text DEFAULT NULL;
+
+-- Deleted posts are kept as empty tombstones so reblog chains through them stay intact,
+-- reblogs then show that the post was deleted in its place
+ALTER TABLE posts ADD COLUMN deleted text DEFAULT NULL;
+
+CREATE TABLE IF NOT EXISTS post_revisions
+(
+    id INTEGER PRIMARY KEY NOT NULL,
+    post_id INTEGER NOT NULL,
+    created text NOT NULL DEFAULT CURRENT_TIMESTAMP, -- When this revision was replaced
+    summary text,
+    body text NOT NULL,
+    FOREIGN KEY (post_id) REFERENCES posts (id) ON DELETE CASCADE
+);
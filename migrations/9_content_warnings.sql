@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN cw_expand integer NOT NULL CHECK (cw_expand in (0, 1)) DEFAULT 0;
+ALTER TABLE users ADD COLUMN cw_hidden_words text NOT NULL DEFAULT ''; -- Comma separated
@@ -1,15 +1,21 @@
+use std::net::SocketAddr;
+
 use anyhow::Result;
+use axum::{extract::DefaultBodyLimit, middleware, Router};
 use axum_login::{login_required, tower_sessions::ExpiredDeletion, AuthManagerLayerBuilder};
 use axum_messages::MessagesManagerLayer;
-use sqlx::{any::install_default_drivers, AnyPool, SqlitePool};
+use sqlx::{any::install_default_drivers, AnyPool};
 use tokio::{signal, task::AbortHandle};
-use tower_sessions::{cookie::{time::Duration, Key}, Expiry, SessionManagerLayer};
-use tower_sessions_sqlx_store::SqliteStore;
+use tower_sessions::{cookie::time::Duration, Expiry, SessionManagerLayer};
 
-use crate::{config::Config, routes::{auth, protected, public}, authentication::Backend};
+use crate::{config::Config, mail::{DumpTransport, Mailer, SmtpTransport}, media::{LocalMediaStore, Media, MAX_ATTACHMENTS}, passkey::RelyingParty, routes::{auth, protected, public}, authentication::Backend, hub::{Hub, LiveEvent}, session::{resave_resigned, rotate_keys, track_session, DbSessionStore, SigningKeys, AUTH_DATA_KEY, SESSION_COOKIE}};
 
 pub struct App {
     db: AnyPool,
+    media: Media,
+    keys: SigningKeys,
+    mailer: Mailer,
+    relying_party: RelyingParty,
 }
 
 impl App {
@@ -17,43 +23,62 @@ impl App {
         install_default_drivers();
         let db = AnyPool::connect_lazy(&config.database_url)?;
         sqlx::migrate!().run(&db).await?;
-        Ok(Self { db })
+        let media = Media::new(LocalMediaStore::new(&config.media_path).await?, config.max_upload_size);
+        let keys = SigningKeys::load(&config.session_keys, &config.session_key_file).await?;
+        let mailer = match config.mail_transport.as_str() {
+            "smtp" => Mailer::new(SmtpTransport::new(&config.smtp_url)?, &config.mail_from, &config.base_url)?,
+            "file" => Mailer::new(DumpTransport::new(Some(config.mail_path.into())).await?, &config.mail_from, &config.base_url)?,
+            "stdout" => Mailer::new(DumpTransport::new(None).await?, &config.mail_from, &config.base_url)?,
+            other => return Err(anyhow::anyhow!("Unknown mail transport {}", other)),
+        };
+        let relying_party = RelyingParty::new(&config.base_url)?;
+        Ok(Self { db, media, keys, mailer, relying_party })
     }
     pub async fn serve(self) -> Result<()> {
-        // Sqlite only session storage
-        let session_store = SqliteStore::new(SqlitePool::connect(":memory:").await?);
-        session_store.migrate().await?;
-
         let deletion = tokio::task::spawn(
-            session_store.clone().continuously_delete_expired(tokio::time::Duration::from_secs(60))
+            DbSessionStore::new(self.db.clone()).continuously_delete_expired(tokio::time::Duration::from_secs(60))
         );
+        let (app, hub) = self.router().await?;
 
-        let key = Key::generate();
-        let session_layer = SessionManagerLayer::new(session_store)
+        let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(shutdown_signal(deletion.abort_handle(), hub))
+            .await?;
+        deletion.await??;
+        Ok(())
+    }
+
+    /// Every route with the layers in front of them, and the hub live streams listen on.
+    /// Requests need the client's address as `ConnectInfo`
+    pub async fn router(self) -> Result<(Router, Hub)> {
+        let session_layer = SessionManagerLayer::new(DbSessionStore::new(self.db.clone()))
+            .with_name(SESSION_COOKIE)
             .with_secure(false)
             .with_expiry(Expiry::OnInactivity(Duration::days(1)))
-            .with_signed(key);
+            .with_signed(self.keys.current.clone());
 
-        let backend = Backend::new(self.db);
-        let auth_layer = AuthManagerLayerBuilder::new(backend, session_layer).build();
+        // Room for every attachment plus the rest of the form
+        let body_limit = self.media.max_size * MAX_ATTACHMENTS + 1024 * 1024;
+        let backend = Backend::new(self.db, self.media, self.mailer, self.keys.clone(), self.relying_party);
+        backend.resume_media_processing().await?;
+        let hub = backend.hub.clone();
+        let auth_layer = AuthManagerLayerBuilder::new(backend, session_layer).with_data_key(AUTH_DATA_KEY).build();
 
         let app = protected::router()
             .route_layer(login_required!(Backend, login_url = "/login"))
             .merge(auth::router())
             .merge(public::router())
+            .layer(DefaultBodyLimit::max(body_limit))
             .layer(MessagesManagerLayer)
-            .layer(auth_layer);
-
-        let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-        axum::serve(listener, app.into_make_service())
-            .with_graceful_shutdown(shutdown_signal(deletion.abort_handle()))
-            .await?;
-        deletion.await??;
-        Ok(())
+            .layer(middleware::from_fn(track_session))
+            .layer(middleware::from_fn(resave_resigned))
+            .layer(auth_layer)
+            .layer(middleware::from_fn_with_state(self.keys, rotate_keys));
+        Ok((app, hub))
     }
 }
 
-async fn shutdown_signal(deletion_task_abort_handle: AbortHandle) {
+async fn shutdown_signal(deletion_task_abort_handle: AbortHandle, hub: Hub) {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -75,4 +100,6 @@ async fn shutdown_signal(deletion_task_abort_handle: AbortHandle) {
         _ = ctrl_c => { deletion_task_abort_handle.abort() },
         _ = terminate => { deletion_task_abort_handle.abort() },
     }
+    // Live streams never finish by themselves and would hold up the shutdown
+    hub.publish(LiveEvent::Shutdown);
 }
\ No newline at end of file
@@ -1,12 +1,15 @@
+use std::collections::HashMap;
+
 use anyhow::Result;
 use axum::async_trait;
 use axum_login::{AuthUser, AuthnBackend, UserId};
 use password_auth::{generate_hash, verify_password};
-use sqlx::AnyPool;
+use sqlx::{AnyConnection, AnyPool};
 use thiserror::Error;
 use tokio::task;
+use tower_sessions::cookie::time::OffsetDateTime;
 
-use crate::{model::{AuthUser as User, DisplayUser, RawPost, Thread}, param::{LoginCredentials, RegisterCredentials}};
+use crate::{hub::{Hub, LiveEvent}, mail::{Email, Mailer}, media::{process_image, Media, ProcessedImage}, model::{ActiveSession, AuthUser as User, ContentFilter, DisplayUser, Note, Notification, NotificationKind, Passkey, RawPost, Reply, Revision, Thread, TwoFactor}, param::{LoginCredentials, PostDetails, RegisterCredentials, Upload}, passkey::{self, Assertion, Challenge, Registration, RelyingParty}, render::{entities, render, Format}, session::SigningKeys, token::{self, Purpose}, two_factor::{self, Verification}};
 
 impl AuthUser for User {
     type Id = i64;
@@ -20,14 +23,32 @@ impl AuthUser for User {
     }
 }
 
+const FOLLOW_PAGE_SIZE: i64 = 50;
+pub const DASH_PAGE_SIZE: i64 = 20;
+const NOTES_PAGE_SIZE: i64 = 50;
+const NOTIFICATIONS_PAGE_SIZE: i64 = 50;
+
 #[derive(Clone, Debug)]
 pub struct Backend {
-    pub db: AnyPool
+    pub db: AnyPool,
+    pub media: Media,
+    pub hub: Hub,
+    pub mailer: Mailer,
+    /// Signs the tokens in emailed links
+    keys: SigningKeys,
+    pub relying_party: RelyingParty,
 }
 
 impl Backend {
-    pub fn new(db: AnyPool) -> Self {
-        Self { db }
+    pub fn new(db: AnyPool, media: Media, mailer: Mailer, keys: SigningKeys, relying_party: RelyingParty) -> Self {
+        Self { db, media, hub: Hub::new(), mailer, keys, relying_party }
+    }
+
+    /// Tells live streams about notifications, once they've been committed
+    fn publish_notifications(&self, users: impl IntoIterator<Item = i64>) {
+        for user_id in users {
+            self.hub.publish(LiveEvent::Notification { user_id });
+        }
     }
 
     pub async fn register(&self, credentials: &RegisterCredentials) -> Result<Option<LoginCredentials>, Error> {
@@ -45,13 +66,17 @@ impl Backend {
             None => {
                 let password_hash = generate_hash(&credentials.password);
                 println!("Account doesn't exist: {}", password_hash);
-                sqlx::query("INSERT INTO users (username, email, password) VALUES ($1, $2, $3);")
+                let (user_id, ): (i64, ) = sqlx::query_as("INSERT INTO users (username, email, password) VALUES ($1, $2, $3) RETURNING id")
                     .bind(&credentials.username)
                     .bind(&credentials.email)
                     .bind(password_hash)
-                    .execute(&self.db)
+                    .fetch_one(&self.db)
                     .await?;
                 println!("Account created");
+                // The account is usable without it, the link can be sent again from the dashboard
+                if let Err(e) = self.send_verification(user_id, &credentials.email).await {
+                    println!("{:?}", e);
+                }
                 Ok(Some(LoginCredentials::from(credentials)))
             }
         }
@@ -68,30 +93,1157 @@ impl Backend {
         }
     }
 
-    pub async fn get_posts(&self, user_id: i64) -> Result<Vec<RawPost>> {
-        let posts: Vec<RawPost> = sqlx::query_as("SELECT posts.id, users.username, thread, created, summary, body FROM posts INNER JOIN users ON posts.user_id = users.id WHERE user_id = $1 ORDER BY created DESC LIMIT 50")
+    /// Locked accounts' posts are only visible to the account itself and its accepted followers
+    pub async fn can_view_posts(&self, viewer: Option<i64>, author: i64) -> Result<bool> {
+        let visible = sqlx::query("SELECT id FROM users WHERE id = $2 AND (approve_followers = 0 OR EXISTS (SELECT 1 FROM follows WHERE follower = $1 AND followee = users.id AND is_accepted = 1))")
+            .bind(viewer)
+            .bind(author)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(visible.is_some())
+    }
+
+    /// Only public posts can be reblogged, and posts by locked accounts count as followers-only
+    pub async fn can_reblog(&self, post_id: i64) -> Result<bool> {
+        let allowed = sqlx::query("SELECT posts.id FROM posts INNER JOIN users ON posts.user_id = users.id WHERE posts.id = $1 AND posts.deleted IS NULL AND posts.visibility = 'public' AND users.approve_followers = 0")
+            .bind(post_id)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(allowed.is_some())
+    }
+
+    /// Returns whether the follow was accepted immediately or is pending approval
+    pub async fn follow(&self, follower: i64, followee: i64) -> Result<bool> {
+        let (is_accepted, ): (i64, ) = sqlx::query_as("SELECT 1 - approve_followers FROM users WHERE id = $1")
+            .bind(followee)
+            .fetch_one(&self.db)
+            .await?;
+        let mut conn = self.db.acquire().await?;
+        let inserted = sqlx::query("INSERT INTO follows (follower, followee, is_accepted, accepted) VALUES ($1, $2, $3, CASE WHEN $3 = 1 THEN CURRENT_TIMESTAMP END) ON CONFLICT DO NOTHING")
+            .bind(follower)
+            .bind(followee)
+            .bind(is_accepted)
+            .execute(&mut *conn)
+            .await?;
+        if inserted.rows_affected() > 0 {
+            let kind = match is_accepted == 1 {
+                true => NotificationKind::Follow,
+                false => NotificationKind::FollowRequest,
+            };
+            let notified = notify(&mut conn, followee, follower, kind, None).await?;
+            self.publish_notifications(notified);
+        }
+        Ok(is_accepted == 1)
+    }
+
+    /// Whether `follower` has an accepted follow of `followee`, which includes following themselves
+    pub async fn is_following(&self, follower: i64, followee: i64) -> Result<bool> {
+        let follow = sqlx::query("SELECT follower FROM follows WHERE follower = $1 AND followee = $2 AND is_accepted = 1")
+            .bind(follower)
+            .bind(followee)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(follow.is_some())
+    }
+
+    /// Also withdraws a pending follow request; the self-follow can't be removed
+    pub async fn unfollow(&self, follower: i64, followee: i64) -> Result<()> {
+        sqlx::query("DELETE FROM follows WHERE follower = $1 AND followee = $2 AND follower != followee")
+            .bind(follower)
+            .bind(followee)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns a page of accepted followers, excluding the user's self-follow, with whether a further page exists
+    pub async fn get_followers(&self, user_id: i64, page: i64) -> Result<(Vec<DisplayUser>, bool)> {
+        let mut followers: Vec<DisplayUser> = sqlx::query_as("SELECT id, username, display_name, bio FROM users INNER JOIN follows ON follows.follower = users.id WHERE follows.followee = $1 AND follows.follower != follows.followee AND follows.is_accepted = 1 ORDER BY follows.accepted DESC, users.id LIMIT $2 OFFSET $3")
+            .bind(user_id)
+            .bind(FOLLOW_PAGE_SIZE + 1)
+            .bind(page * FOLLOW_PAGE_SIZE)
+            .fetch_all(&self.db)
+            .await?;
+        let has_next = followers.len() as i64 > FOLLOW_PAGE_SIZE;
+        followers.truncate(FOLLOW_PAGE_SIZE as usize);
+        Ok((followers, has_next))
+    }
+
+    /// Returns a page of accepted followees, excluding the user's self-follow, with whether a further page exists
+    pub async fn get_following(&self, user_id: i64, page: i64) -> Result<(Vec<DisplayUser>, bool)> {
+        let mut following: Vec<DisplayUser> = sqlx::query_as("SELECT id, username, display_name, bio FROM users INNER JOIN follows ON follows.followee = users.id WHERE follows.follower = $1 AND follows.follower != follows.followee AND follows.is_accepted = 1 ORDER BY follows.accepted DESC, users.id LIMIT $2 OFFSET $3")
+            .bind(user_id)
+            .bind(FOLLOW_PAGE_SIZE + 1)
+            .bind(page * FOLLOW_PAGE_SIZE)
+            .fetch_all(&self.db)
+            .await?;
+        let has_next = following.len() as i64 > FOLLOW_PAGE_SIZE;
+        following.truncate(FOLLOW_PAGE_SIZE as usize);
+        Ok((following, has_next))
+    }
+
+    pub async fn get_follow_requests(&self, user_id: i64) -> Result<Vec<DisplayUser>> {
+        let requests: Vec<DisplayUser> = sqlx::query_as("SELECT id, username, display_name, bio FROM users INNER JOIN follows ON follows.follower = users.id WHERE follows.followee = $1 AND follows.is_accepted = 0 ORDER BY follows.requested")
+            .bind(user_id)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(requests)
+    }
+
+    pub async fn accept_follow_request(&self, user_id: i64, follower: i64) -> Result<()> {
+        sqlx::query("UPDATE follows SET is_accepted = 1, accepted = CURRENT_TIMESTAMP WHERE followee = $1 AND follower = $2 AND is_accepted = 0")
+            .bind(user_id)
+            .bind(follower)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn reject_follow_request(&self, user_id: i64, follower: i64) -> Result<()> {
+        sqlx::query("DELETE FROM follows WHERE followee = $1 AND follower = $2 AND is_accepted = 0")
+            .bind(user_id)
+            .bind(follower)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn approves_followers(&self, user_id: i64) -> Result<bool> {
+        let (approve_followers, ): (i64, ) = sqlx::query_as("SELECT approve_followers FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&self.db)
+            .await?;
+        Ok(approve_followers == 1)
+    }
+
+    /// Unlocking an account accepts any outstanding follow requests
+    pub async fn set_approve_followers(&self, user_id: i64, approve_followers: bool) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        sqlx::query("UPDATE users SET approve_followers = $2 WHERE id = $1")
+            .bind(user_id)
+            .bind(approve_followers as i64)
+            .execute(&mut *tx)
+            .await?;
+        if !approve_followers {
+            sqlx::query("UPDATE follows SET is_accepted = 1, accepted = CURRENT_TIMESTAMP WHERE followee = $1 AND is_accepted = 0")
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn send_verification(&self, user_id: i64, email: &str) -> Result<()> {
+        let token = token::issue(&self.keys, Purpose::VerifyEmail, user_id, email);
+        self.mailer.send(&Email {
+            to: email.to_string(),
+            subject: String::from("Verify your email address"),
+            body: format!(
+                "Follow this link to verify your email address and start posting:\n\n{}/verify-email?token={}\n\nThe link expires in two days.",
+                self.mailer.base_url, token
+            ),
+        }).await
+    }
+
+    /// Returns whether the token was valid for the address the account currently has
+    pub async fn verify_email(&self, token: &str) -> Result<bool> {
+        let user_id = match token::user_id(token) {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+        let email: Option<(String, )> = sqlx::query_as("SELECT email FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(&self.db)
+            .await?;
+        match email {
+            Some((email, )) if token::verify(&self.keys, Purpose::VerifyEmail, token, &email) => {
+                sqlx::query("UPDATE users SET email_verified = 1 WHERE id = $1")
+                    .bind(user_id)
+                    .execute(&self.db)
+                    .await?;
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+
+    /// Emails a reset link if an account has this address, without saying whether one does
+    pub async fn send_password_reset(&self, email: &str) -> Result<()> {
+        let user: Option<(i64, String, String)> = sqlx::query_as("SELECT id, email, password FROM users WHERE email = $1")
+            .bind(email.trim())
+            .fetch_optional(&self.db)
+            .await?;
+        let (user_id, email, password) = match user {
+            Some(u) => u,
+            None => return Ok(()),
+        };
+        // Signing over the password hash means the link stops working once it's been used
+        let token = token::issue(&self.keys, Purpose::ResetPassword, user_id, &password);
+        let email = Email {
+            to: email,
+            subject: String::from("Reset your password"),
+            body: format!(
+                "Follow this link to choose a new password:\n\n{}/reset-password?token={}\n\nThe link expires in an hour. If you didn't ask to reset your password you can ignore this email.",
+                self.mailer.base_url, token
+            ),
+        };
+        // Sent in the background, since waiting on the mail server would make the response
+        // slower exactly when the address belongs to an account
+        let mailer = self.mailer.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mailer.send(&email).await {
+                println!("{:?}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// The password hash a reset token was issued against, if the token is still good
+    async fn reset_token_password(&self, token: &str) -> Result<Option<(i64, String)>> {
+        let user_id = match token::user_id(token) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let password: Option<(String, )> = sqlx::query_as("SELECT password FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(password
+            .filter(|(p, )| token::verify(&self.keys, Purpose::ResetPassword, token, p))
+            .map(|(p, )| (user_id, p)))
+    }
+
+    /// Whether a reset token is still good, checked before showing the form
+    pub async fn check_reset_token(&self, token: &str) -> Result<bool> {
+        Ok(self.reset_token_password(token).await?.is_some())
+    }
+
+    /// Sets a new password from a reset link, logging out every session of the account.
+    /// Following the link proves the email address too
+    pub async fn reset_password(&self, token: &str, password: String) -> Result<bool> {
+        let (user_id, old) = match self.reset_token_password(token).await? {
+            Some(found) => found,
+            None => return Ok(false),
+        };
+        let password = task::spawn_blocking(move || generate_hash(password)).await?;
+        let mut tx = self.db.begin().await?;
+        // Someone else using the same link first changes the hash and this matches nothing
+        let updated = sqlx::query("UPDATE users SET password = $2, email_verified = 1 WHERE id = $1 AND password = $3")
+            .bind(user_id)
+            .bind(password)
+            .bind(old)
+            .execute(&mut *tx)
+            .await?;
+        if updated.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query("DELETE FROM sessions WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        // Whoever the account is being recovered from may have added a passkey of their own
+        sqlx::query("DELETE FROM passkeys WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        self.hub.publish(LiveEvent::SessionsRevoked { user_id });
+        Ok(true)
+    }
+
+    /// Sets a new password if `current` is right, logging out every other session of the account.
+    /// Returns the updated user so the session in `keep` can log in again with the new password
+    pub async fn change_password(&self, user: &User, current: String, new: String, keep: Option<String>) -> Result<Option<User>> {
+        let hash = user.password.clone();
+        let password = task::spawn_blocking(move || {
+            verify_password(current, &hash).ok().map(|_| generate_hash(new))
+        }).await?;
+        let password = match password {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        let mut tx = self.db.begin().await?;
+        let user: User = sqlx::query_as("UPDATE users SET password = $2 WHERE id = $1 RETURNING *")
+            .bind(user.id)
+            .bind(password)
+            .fetch_one(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM sessions WHERE user_id = $1 AND ($2 IS NULL OR id != $2)")
+            .bind(user.id)
+            .bind(keep)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        self.hub.publish(LiveEvent::SessionsRevoked { user_id: user.id });
+        Ok(Some(user))
+    }
+
+    pub async fn get_sessions(&self, user_id: i64) -> Result<Vec<ActiveSession>> {
+        let sessions: Vec<ActiveSession> = sqlx::query_as("SELECT id, created, last_seen, user_agent, ip FROM sessions WHERE user_id = $1 AND expiry_date > $2 ORDER BY last_seen DESC")
+            .bind(user_id)
+            .bind(OffsetDateTime::now_utc().unix_timestamp())
+            .fetch_all(&self.db)
+            .await?;
+        Ok(sessions)
+    }
+
+    pub async fn revoke_session(&self, user_id: i64, session_id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM sessions WHERE user_id = $1 AND id = $2")
+            .bind(user_id)
+            .bind(session_id)
+            .execute(&self.db)
+            .await?;
+        self.hub.publish(LiveEvent::SessionsRevoked { user_id });
+        Ok(())
+    }
+
+    pub async fn revoke_all_sessions(&self, user_id: i64) -> Result<()> {
+        sqlx::query("DELETE FROM sessions WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&self.db)
+            .await?;
+        self.hub.publish(LiveEvent::SessionsRevoked { user_id });
+        Ok(())
+    }
+
+    /// Whether a session is still logged in, rather than revoked or expired
+    pub async fn session_exists(&self, session_id: &str) -> Result<bool> {
+        let session = sqlx::query("SELECT id FROM sessions WHERE id = $1 AND expiry_date > $2")
+            .bind(session_id)
+            .bind(OffsetDateTime::now_utc().unix_timestamp())
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(session.is_some())
+    }
+
+    pub async fn get_two_factor(&self, user_id: i64) -> Result<TwoFactor> {
+        let two_factor: TwoFactor = sqlx::query_as("SELECT totp_secret, totp_enabled, (SELECT COUNT(*) FROM recovery_codes WHERE user_id = users.id) AS recovery_codes FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&self.db)
+            .await?;
+        Ok(two_factor)
+    }
+
+    /// Starts enrolling with a new secret, replacing any from an earlier unfinished attempt
+    pub async fn start_two_factor(&self, user_id: i64) -> Result<()> {
+        sqlx::query("UPDATE users SET totp_secret = $2 WHERE id = $1 AND totp_enabled = 0")
+            .bind(user_id)
+            .bind(two_factor::generate_secret())
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Finishes enrolling if `code` matches the secret being enrolled.
+    /// Returns the new recovery codes, which are only stored hashed so can't be shown again
+    pub async fn enable_two_factor(&self, user_id: i64, code: &str) -> Result<Option<Vec<String>>> {
+        let secret = match self.get_two_factor(user_id).await? {
+            TwoFactor { totp_secret: Some(secret), totp_enabled: 0, .. } => secret,
+            _ => return Ok(None),
+        };
+        let step = match two_factor::accepted_step(&secret, code.trim()) {
+            Some(step) => step,
+            None => return Ok(None),
+        };
+        let mut tx = self.db.begin().await?;
+        let updated = sqlx::query("UPDATE users SET totp_enabled = 1, totp_last_step = $2 WHERE id = $1 AND totp_secret = $3 AND totp_enabled = 0")
+            .bind(user_id)
+            .bind(step)
+            .bind(&secret)
+            .execute(&mut *tx)
+            .await?;
+        if updated.rows_affected() == 0 {
+            return Ok(None);
+        }
+        let codes = two_factor::generate_recovery_codes();
+        replace_recovery_codes(&mut tx, user_id, &codes).await?;
+        tx.commit().await?;
+        Ok(Some(codes))
+    }
+
+    /// Checks the second step of a login, either a code from the authenticator app or an unused recovery code.
+    /// Either only works once
+    pub async fn verify_second_factor(&self, user_id: i64, code: &str) -> Result<Verification> {
+        let secret = match self.get_two_factor(user_id).await? {
+            TwoFactor { totp_secret: Some(secret), totp_enabled: 1, .. } => secret,
+            _ => return Ok(Verification::Rejected),
+        };
+        // Every code counts against the account before it's checked, so attempts racing each other
+        // can't get past the limit. The count starts over once a lockout has passed
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let counted = sqlx::query(
+            "UPDATE users SET
+                totp_failures = CASE WHEN totp_locked_until IS NULL THEN totp_failures + 1 ELSE 1 END,
+                totp_locked_until = CASE WHEN totp_locked_until IS NULL AND totp_failures + 1 >= $3 THEN $2 + $4 END
+            WHERE id = $1 AND (totp_locked_until IS NULL OR totp_locked_until <= $2)"
+        )
+            .bind(user_id)
+            .bind(now)
+            .bind(two_factor::MAX_FAILURES)
+            .bind(two_factor::LOCKOUT.whole_seconds())
+            .execute(&self.db)
+            .await?;
+        if counted.rows_affected() == 0 {
+            return Ok(Verification::LockedOut);
+        }
+        let accepted = match two_factor::accepted_step(&secret, code.trim()) {
+            Some(step) => sqlx::query("UPDATE users SET totp_last_step = $2 WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)")
+                .bind(user_id)
+                .bind(step)
+                .execute(&self.db)
+                .await?,
+            None => sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1 AND code_hash = $2")
+                .bind(user_id)
+                .bind(two_factor::hash_recovery_code(code))
+                .execute(&self.db)
+                .await?,
+        }.rows_affected() == 1;
+        if !accepted {
+            return Ok(Verification::Rejected);
+        }
+        sqlx::query("UPDATE users SET totp_failures = 0, totp_locked_until = NULL WHERE id = $1")
+            .bind(user_id)
+            .execute(&self.db)
+            .await?;
+        Ok(Verification::Accepted)
+    }
+
+    /// Whether `password` is the user's current one, asked for again before changing how they log in
+    pub async fn check_password(&self, user: &User, password: String) -> Result<bool> {
+        let hash = user.password.clone();
+        Ok(task::spawn_blocking(move || verify_password(password, &hash)).await?.is_ok())
+    }
+
+    /// Turns two-factor authentication off if `password` is the user's current one
+    pub async fn disable_two_factor(&self, user: &User, password: String) -> Result<bool> {
+        if !self.check_password(user, password).await? {
+            return Ok(false);
+        }
+        let mut tx = self.db.begin().await?;
+        sqlx::query("UPDATE users SET totp_secret = NULL, totp_enabled = 0, totp_last_step = NULL, totp_failures = 0, totp_locked_until = NULL WHERE id = $1")
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+        replace_recovery_codes(&mut tx, user.id, &[]).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    pub async fn get_passkeys(&self, user_id: i64) -> Result<Vec<Passkey>> {
+        let passkeys: Vec<Passkey> = sqlx::query_as("SELECT id, name, created, last_used FROM passkeys WHERE user_id = $1 ORDER BY created")
             .bind(user_id)
             .fetch_all(&self.db)
             .await?;
+        Ok(passkeys)
+    }
+
+    /// Stores a new passkey if it answers the challenge the user was given.
+    /// Returns whether it was added, which it isn't if it's already registered
+    pub async fn add_passkey(&self, user_id: i64, challenge: &Challenge, registration: &Registration) -> Result<bool> {
+        let passkey = match self.relying_party.verify_registration(challenge, registration) {
+            Some(p) => p,
+            None => return Ok(false),
+        };
+        let name = match registration.name.trim() {
+            "" => "Passkey",
+            name => name,
+        };
+        let added = sqlx::query("INSERT INTO passkeys (id, user_id, name, public_key, sign_count) VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING")
+            .bind(passkey.id)
+            .bind(user_id)
+            .bind(name)
+            .bind(passkey.public_key)
+            .bind(passkey.sign_count)
+            .execute(&self.db)
+            .await?;
+        Ok(added.rows_affected() == 1)
+    }
+
+    pub async fn delete_passkey(&self, user_id: i64, passkey_id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM passkeys WHERE user_id = $1 AND id = $2")
+            .bind(user_id)
+            .bind(passkey_id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// The user a passkey assertion logs in as, if it's signed by one of their passkeys
+    async fn verify_passkey(&self, challenge: &Challenge, assertion: &Assertion) -> Result<Option<User>, Error> {
+        let passkey: Option<(i64, String, i64)> = sqlx::query_as("SELECT user_id, public_key, sign_count FROM passkeys WHERE id = $1")
+            .bind(&assertion.id)
+            .fetch_optional(&self.db)
+            .await?;
+        let (user_id, public_key, sign_count) = match passkey {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        if assertion.user_handle.as_ref().is_some_and(|handle| *handle != passkey::user_handle(user_id)) {
+            return Ok(None);
+        }
+        let new_count = match self.relying_party.verify_assertion(challenge, assertion, &public_key, sign_count) {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        // Another login with the same passkey moving the counter first means one of them is a replay
+        let updated = sqlx::query("UPDATE passkeys SET sign_count = $2, last_used = CURRENT_TIMESTAMP WHERE id = $1 AND sign_count = $3")
+            .bind(&assertion.id)
+            .bind(new_count)
+            .bind(sign_count)
+            .execute(&self.db)
+            .await?;
+        if updated.rows_affected() == 0 {
+            return Ok(None);
+        }
+        let user = sqlx::query_as("SELECT * FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(user)
+    }
+
+    /// Logged out viewers get the default filter
+    pub async fn get_content_filter(&self, viewer: Option<i64>) -> Result<ContentFilter> {
+        let user_id = match viewer {
+            Some(id) => id,
+            None => return Ok(ContentFilter::default()),
+        };
+        let (cw_expand, cw_hidden_words): (i64, String) = sqlx::query_as("SELECT cw_expand, cw_hidden_words FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&self.db)
+            .await?;
+        Ok(ContentFilter::new(cw_expand == 1, &cw_hidden_words))
+    }
+
+    pub async fn set_content_filter(&self, user_id: i64, filter: &ContentFilter) -> Result<()> {
+        sqlx::query("UPDATE users SET cw_expand = $2, cw_hidden_words = $3 WHERE id = $1")
+            .bind(user_id)
+            .bind(filter.expand as i64)
+            .bind(filter.hidden_words.join(", "))
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_posts(&self, user_id: i64, viewer: Option<i64>) -> Result<Vec<RawPost>> {
+        let query = format!("SELECT posts.id, users.username, created, summary, body FROM posts INNER JOIN users ON posts.user_id = users.id WHERE user_id = $1 AND deleted IS NULL AND {} ORDER BY created DESC LIMIT 50", visible_to("$2"));
+        let posts: Vec<RawPost> = sqlx::query_as(&query)
+            .bind(user_id)
+            .bind(viewer)
+            .fetch_all(&self.db)
+            .await?;
         Ok(posts)
     }
 
-    pub async fn get_dash_contents(&self, user_id: i64) -> Result<Vec<Thread>> {
-        let mut result = Vec::new();
-        let follows: Vec<DisplayUser> = sqlx::query_as("SELECT id, username, display_name, bio FROM users INNER JOIN follows ON follows.followee = users.id WHERE follows.follower = $1")
+    /// Only public posts are listed, and those by locked accounts only when `viewer` is an accepted follower
+    pub async fn get_tag_posts(&self, tag: &str, viewer: Option<i64>) -> Result<Vec<RawPost>> {
+        let posts: Vec<RawPost> = sqlx::query_as("SELECT posts.id, users.username, created, summary, body FROM posts INNER JOIN users ON posts.user_id = users.id INNER JOIN postTags ON postTags.post_id = posts.id INNER JOIN tags ON tags.id = postTags.tag_id WHERE tags.tag = $1 AND posts.deleted IS NULL AND posts.visibility = 'public' AND (users.approve_followers = 0 OR EXISTS (SELECT 1 FROM follows WHERE follower = $2 AND followee = users.id AND is_accepted = 1)) ORDER BY created DESC LIMIT 50")
+            .bind(tag)
+            .bind(viewer)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(posts)
+    }
+
+    /// Stores the current body as a revision before replacing it, returns false if `user_id` can't edit the post
+    pub async fn edit_post(&self, user_id: i64, post_id: i64, post: &PostDetails) -> Result<bool> {
+        let mut tx = self.db.begin().await?;
+        let revision = sqlx::query("INSERT INTO post_revisions (post_id, summary, body) SELECT id, summary, body FROM posts WHERE id = $1 AND user_id = $2 AND deleted IS NULL")
+            .bind(post_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        if revision.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query("UPDATE posts SET summary = $2, body = $3, format = $4, reply_permission = $5, edited = CURRENT_TIMESTAMP WHERE id = $1")
+            .bind(post_id)
+            .bind(post.summary())
+            .bind(&post.body)
+            .bind(post.format.as_str())
+            .bind(post.replies.as_str())
+            .execute(&mut *tx)
+            .await?;
+        let notified = add_contents(&mut tx, post_id, post).await?;
+        tx.commit().await?;
+        self.publish_notifications(notified);
+        Ok(true)
+    }
+
+    /// Leaves an empty tombstone in place of the post, returns false if `user_id` can't delete the post
+    pub async fn delete_post(&self, user_id: i64, post_id: i64) -> Result<bool> {
+        let mut tx = self.db.begin().await?;
+        let deleted = sqlx::query("UPDATE posts SET body = '', body_html = '', summary = NULL, deleted = CURRENT_TIMESTAMP WHERE id = $1 AND user_id = $2 AND deleted IS NULL")
+            .bind(post_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        if deleted.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query("DELETE FROM post_revisions WHERE post_id = $1")
+            .bind(post_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM postTags WHERE post_id = $1")
+            .bind(post_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM mentions WHERE post_id = $1")
+            .bind(post_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM likes WHERE post_id = $1")
+            .bind(post_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM replies WHERE post_id = $1")
+            .bind(post_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM notifications WHERE post_id = $1")
+            .bind(post_id)
+            .execute(&mut *tx)
+            .await?;
+        let mut media: Vec<(String, )> = sqlx::query_as("DELETE FROM media_variants WHERE media_id IN (SELECT id FROM media WHERE post_id = $1) RETURNING storage_key")
+            .bind(post_id)
+            .fetch_all(&mut *tx)
+            .await?;
+        media.extend(
+            sqlx::query_as("DELETE FROM media WHERE post_id = $1 RETURNING storage_key")
+                .bind(post_id)
+                .fetch_all(&mut *tx)
+                .await?
+        );
+        tx.commit().await?;
+        self.discard_media(&media.into_iter().map(|m| m.0).collect::<Vec<_>>()).await;
+        Ok(true)
+    }
+
+    /// Returns the post's author, or `None` if the post doesn't exist or can't be seen by the user
+    pub async fn like(&self, user_id: i64, post_id: i64) -> Result<Option<String>> {
+        let query = format!("SELECT users.id, users.username FROM posts INNER JOIN users ON posts.user_id = users.id WHERE posts.id = $2 AND posts.deleted IS NULL AND {}", visible_to("$1"));
+        let author: Option<(i64, String)> = sqlx::query_as(&query)
+            .bind(user_id)
+            .bind(post_id)
+            .fetch_optional(&self.db)
+            .await?;
+        if let Some((author_id, _)) = author {
+            let mut conn = self.db.acquire().await?;
+            let inserted = sqlx::query("INSERT INTO likes (user_id, post_id) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+                .bind(user_id)
+                .bind(post_id)
+                .execute(&mut *conn)
+                .await?;
+            if inserted.rows_affected() > 0 {
+                let notified = notify(&mut conn, author_id, user_id, NotificationKind::Like, Some(post_id)).await?;
+                self.publish_notifications(notified);
+            }
+        }
+        Ok(author.map(|a| a.1))
+    }
+
+    pub async fn unlike(&self, user_id: i64, post_id: i64) -> Result<()> {
+        sqlx::query("DELETE FROM likes WHERE user_id = $1 AND post_id = $2")
+            .bind(user_id)
+            .bind(post_id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns a page of the posts a user has liked which the viewer can see, most recent like first
+    pub async fn get_liked_posts(&self, user_id: i64, viewer: Option<i64>, page: i64) -> Result<(Vec<RawPost>, bool)> {
+        let query = format!("SELECT posts.id, users.username, posts.created, summary, body FROM posts INNER JOIN users ON posts.user_id = users.id INNER JOIN likes ON likes.post_id = posts.id WHERE likes.user_id = $1 AND posts.deleted IS NULL AND {} ORDER BY likes.created DESC, posts.id DESC LIMIT $3 OFFSET $4", visible_to("$2"));
+        let mut posts: Vec<RawPost> = sqlx::query_as(&query)
+            .bind(user_id)
+            .bind(viewer)
+            .bind(DASH_PAGE_SIZE + 1)
+            .bind(page * DASH_PAGE_SIZE)
+            .fetch_all(&self.db)
+            .await?;
+        let has_next = posts.len() as i64 > DASH_PAGE_SIZE;
+        posts.truncate(DASH_PAGE_SIZE as usize);
+        Ok((posts, has_next))
+    }
+
+    pub async fn get_notifications(&self, user_id: i64, page: i64) -> Result<(Vec<Notification>, bool)> {
+        let mut notifications: Vec<Notification> = sqlx::query_as(
+            "SELECT notifications.id, notifications.kind, actors.username AS actor, notifications.post_id, authors.username AS post_author, notifications.is_read, notifications.created
+            FROM notifications
+            INNER JOIN users AS actors ON actors.id = notifications.actor_id
+            LEFT JOIN posts ON posts.id = notifications.post_id
+            LEFT JOIN users AS authors ON authors.id = posts.user_id
+            WHERE notifications.user_id = $1
+            ORDER BY notifications.id DESC
+            LIMIT $2 OFFSET $3"
+        )
             .bind(user_id)
+            .bind(NOTIFICATIONS_PAGE_SIZE + 1)
+            .bind(page * NOTIFICATIONS_PAGE_SIZE)
             .fetch_all(&self.db)
             .await?;
-        for follow in follows {
-            let posts = self.get_posts(follow.id).await?;
-            for post in posts {
-                result.push(post.into(&self.db).await?);
+        let has_next = notifications.len() as i64 > NOTIFICATIONS_PAGE_SIZE;
+        notifications.truncate(NOTIFICATIONS_PAGE_SIZE as usize);
+        Ok((notifications, has_next))
+    }
+
+    pub async fn count_unread_notifications(&self, user_id: i64) -> Result<i64> {
+        let (unread, ): (i64, ) = sqlx::query_as("SELECT COUNT(*) FROM notifications WHERE user_id = $1 AND is_read = 0")
+            .bind(user_id)
+            .fetch_one(&self.db)
+            .await?;
+        Ok(unread)
+    }
+
+    pub async fn mark_notifications_read(&self, user_id: i64) -> Result<()> {
+        sqlx::query("UPDATE notifications SET is_read = 1 WHERE user_id = $1 AND is_read = 0")
+            .bind(user_id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_notification_mutes(&self, user_id: i64) -> Result<Vec<NotificationKind>> {
+        let kinds: Vec<(String, )> = sqlx::query_as("SELECT kind FROM notification_mutes WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(kinds.iter().filter_map(|(kind, )| NotificationKind::parse(kind)).collect())
+    }
+
+    pub async fn set_notification_mutes(&self, user_id: i64, muted: &[NotificationKind]) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        sqlx::query("DELETE FROM notification_mutes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        for kind in muted {
+            sqlx::query("INSERT INTO notification_mutes (user_id, kind) VALUES ($1, $2)")
+                .bind(user_id)
+                .bind(kind.as_str())
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Whether the user can see the post and its author lets them reply to it
+    pub async fn can_reply(&self, user_id: i64, post_id: i64) -> Result<bool> {
+        let query = format!(
+            "SELECT posts.id FROM posts INNER JOIN users ON posts.user_id = users.id
+            WHERE posts.id = $2 AND posts.deleted IS NULL
+            AND {}
+            AND (
+                posts.user_id = $1
+                OR posts.reply_permission = 'everyone'
+                OR (posts.reply_permission = 'followers' AND EXISTS (SELECT 1 FROM follows WHERE follower = $1 AND followee = users.id AND is_accepted = 1))
+            )",
+            visible_to("$1")
+        );
+        let allowed = sqlx::query(&query)
+            .bind(user_id)
+            .bind(post_id)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(allowed.is_some())
+    }
+
+    /// Returns false if the user isn't allowed to reply to the post
+    pub async fn reply(&self, user_id: i64, post_id: i64, body: &str) -> Result<bool> {
+        if !self.can_reply(user_id, post_id).await? {
+            return Ok(false);
+        }
+        let mut tx = self.db.begin().await?;
+        let users = find_users(&mut tx, entities(body, Format::Plain).mentions).await?;
+        sqlx::query("INSERT INTO replies (post_id, user_id, body, body_html) VALUES ($1, $2, $3, $4)")
+            .bind(post_id)
+            .bind(user_id)
+            .bind(body)
+            .bind(render(body, Format::Plain, &users.keys().cloned().collect()))
+            .execute(&mut *tx)
+            .await?;
+        let (author_id, ): (i64, ) = sqlx::query_as("SELECT user_id FROM posts WHERE id = $1")
+            .bind(post_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        let mut notified = Vec::new();
+        notified.extend(notify(&mut tx, author_id, user_id, NotificationKind::Reply, Some(post_id)).await?);
+        for mentioned in users.into_values().filter(|id| *id != author_id) {
+            notified.extend(notify(&mut tx, mentioned, user_id, NotificationKind::Mention, Some(post_id)).await?);
+        }
+        tx.commit().await?;
+        self.publish_notifications(notified);
+        Ok(true)
+    }
+
+    pub async fn get_replies(&self, post_id: i64) -> Result<Vec<Reply>> {
+        let replies: Vec<Reply> = sqlx::query_as("SELECT replies.id, users.username, replies.created, replies.body_html FROM replies INNER JOIN users ON replies.user_id = users.id WHERE replies.post_id = $1 ORDER BY replies.id")
+            .bind(post_id)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(replies)
+    }
+
+    /// Returns a page of the notes on the whole reblog tree a post belongs to, oldest first,
+    /// leaving out those on posts the viewer can't see
+    pub async fn get_notes(&self, post_id: i64, viewer: Option<i64>, page: i64) -> Result<(Vec<Note>, bool)> {
+        let query = format!(
+            "WITH root AS (
+                SELECT COALESCE((SELECT ancestor_id FROM post_ancestry WHERE post_id = $1 ORDER BY depth DESC LIMIT 1), $1) AS id
+            ), tree AS (
+                SELECT id FROM root UNION SELECT post_id FROM post_ancestry WHERE ancestor_id = (SELECT id FROM root)
+            ), visible AS (
+                SELECT posts.id FROM posts INNER JOIN users ON posts.user_id = users.id
+                WHERE posts.id IN (SELECT id FROM tree) AND posts.deleted IS NULL AND {}
+            )
+            SELECT 'like' AS kind, users.username, likes.created, likes.post_id, NULL AS parent, NULL AS summary, '' AS body_html
+            FROM likes INNER JOIN users ON likes.user_id = users.id
+            WHERE likes.post_id IN (SELECT id FROM visible)
+            UNION ALL
+            SELECT 'reblog', users.username, posts.created, posts.id,
+                (SELECT parents.username FROM post_ancestry INNER JOIN posts AS parent_posts ON parent_posts.id = post_ancestry.ancestor_id INNER JOIN users AS parents ON parents.id = parent_posts.user_id WHERE post_ancestry.post_id = posts.id AND post_ancestry.depth = 1),
+                posts.summary, posts.body_html
+            FROM posts INNER JOIN users ON posts.user_id = users.id
+            WHERE posts.id IN (SELECT id FROM visible) AND posts.id != (SELECT id FROM root)
+            UNION ALL
+            SELECT 'reply', users.username, replies.created, replies.post_id,
+                (SELECT authors.username FROM posts INNER JOIN users AS authors ON authors.id = posts.user_id WHERE posts.id = replies.post_id),
+                NULL, replies.body_html
+            FROM replies INNER JOIN users ON replies.user_id = users.id
+            WHERE replies.post_id IN (SELECT id FROM visible)
+            ORDER BY created, post_id
+            LIMIT $3 OFFSET $4",
+            visible_to("$2")
+        );
+        let mut notes: Vec<Note> = sqlx::query_as(&query)
+            .bind(post_id)
+            .bind(viewer)
+            .bind(NOTES_PAGE_SIZE + 1)
+            .bind(page * NOTES_PAGE_SIZE)
+            .fetch_all(&self.db)
+            .await?;
+        let has_next = notes.len() as i64 > NOTES_PAGE_SIZE;
+        notes.truncate(NOTES_PAGE_SIZE as usize);
+
+        let reblogs: Vec<i64> = notes.iter().filter(|n| n.kind == "reblog").map(|n| n.post_id).collect();
+        if !reblogs.is_empty() {
+            let tag_query = format!(
+                "SELECT postTags.post_id, tag FROM tags INNER JOIN postTags ON postTags.tag_id = tags.id WHERE postTags.post_id IN ({})",
+                (1..=reblogs.len()).map(|i| format!("${}", i)).collect::<Vec<_>>().join(", ")
+            );
+            let mut query = sqlx::query_as(&tag_query);
+            for id in &reblogs {
+                query = query.bind(id);
+            }
+            let tags: Vec<(i64, String)> = query.fetch_all(&self.db).await?;
+            for note in notes.iter_mut().filter(|n| n.kind == "reblog") {
+                note.tags = tags.iter().filter(|(id, _)| *id == note.post_id).map(|(_, tag)| tag.clone()).collect();
             }
         }
-        Ok(result)
+        Ok((notes, has_next))
+    }
+
+    pub async fn get_revisions(&self, post_id: i64) -> Result<Vec<Revision>> {
+        let revisions: Vec<Revision> = sqlx::query_as("SELECT id, created, summary, body FROM post_revisions WHERE post_id = $1 ORDER BY id DESC")
+            .bind(post_id)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(revisions)
+    }
+
+    pub async fn follow_tag(&self, user_id: i64, tag: &str) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        sqlx::query("INSERT INTO tags (tag) VALUES ($1) ON CONFLICT (tag) DO NOTHING")
+            .bind(tag)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("INSERT INTO tagFollows (user_id, tag_id) SELECT $1, id FROM tags WHERE tag = $2 ON CONFLICT DO NOTHING")
+            .bind(user_id)
+            .bind(tag)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn unfollow_tag(&self, user_id: i64, tag: &str) -> Result<()> {
+        sqlx::query("DELETE FROM tagFollows WHERE user_id = $1 AND tag_id = (SELECT id FROM tags WHERE tag = $2)")
+            .bind(user_id)
+            .bind(tag)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns a post if it exists and `viewer` is allowed to see it
+    pub async fn get_post(&self, post_id: i64, viewer: Option<i64>) -> Result<Option<RawPost>> {
+        let query = format!("SELECT posts.id, users.username, created, summary, body FROM posts INNER JOIN users ON posts.user_id = users.id WHERE posts.id = $1 AND deleted IS NULL AND {}", visible_to("$2"));
+        let post: Option<RawPost> = sqlx::query_as(&query)
+            .bind(post_id)
+            .bind(viewer)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(post)
+    }
+
+    pub async fn create_post(&self, user_id: i64, post: &PostDetails, uploads: &[Upload]) -> Result<i64> {
+        let mut stored = Vec::new();
+        for upload in uploads {
+            let key = Media::generate_key();
+            if let Err(e) = self.media.store.put(&key, &upload.data).await {
+                self.discard_media(&stored).await;
+                return Err(e);
+            }
+            stored.push(key);
+        }
+        let post_id = match self.insert_post(user_id, post, uploads, &stored).await {
+            Ok((id, notified)) => {
+                self.hub.publish(LiveEvent::Post { post_id: id, author_id: user_id });
+                self.publish_notifications(notified);
+                id
+            },
+            Err(e) => {
+                self.discard_media(&stored).await;
+                return Err(e);
+            }
+        };
+        if uploads.iter().any(|u| u.mime.starts_with("image/")) {
+            tokio::spawn(self.clone().process_media());
+        }
+        Ok(post_id)
+    }
+
+    /// Returns the new post's id and who was notified about it
+    async fn insert_post(&self, user_id: i64, post: &PostDetails, uploads: &[Upload], keys: &[String]) -> Result<(i64, Vec<i64>)> {
+        let mut tx = self.db.begin().await?;
+        let (post_id, ): (i64, ) = sqlx::query_as("INSERT INTO posts (user_id, summary, body, format, reply_permission, visibility) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id")
+            .bind(user_id)
+            .bind(post.summary())
+            .bind(&post.body)
+            .bind(post.format.as_str())
+            .bind(post.replies.as_str())
+            .bind(post.visibility.as_str())
+            .fetch_one(&mut *tx)
+            .await?;
+        let notified = add_contents(&mut tx, post_id, post).await?;
+        for (upload, key) in uploads.iter().zip(keys) {
+            // Images aren't served until their metadata has been stripped
+            let status = match upload.mime.starts_with("image/") {
+                true => "pending",
+                false => "ready",
+            };
+            sqlx::query("INSERT INTO media (post_id, user_id, storage_key, mime, size, alt, status) VALUES ($1, $2, $3, $4, $5, $6, $7)")
+                .bind(post_id)
+                .bind(user_id)
+                .bind(key)
+                .bind(upload.mime)
+                .bind(upload.data.len() as i64)
+                .bind(upload.alt.trim())
+                .bind(status)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok((post_id, notified))
+    }
+
+    /// Best effort removal of stored media that's no longer referenced
+    async fn discard_media(&self, keys: &[String]) {
+        for key in keys {
+            if let Err(e) = self.media.store.delete(key).await {
+                println!("Unable to delete media {}: {:?}", key, e);
+            }
+        }
+    }
+
+    /// Processes pending images one at a time until none are left
+    pub async fn process_media(self) {
+        loop {
+            // Claiming the row first keeps concurrent workers from processing the same image
+            let claimed: Result<Option<(i64, String, String)>, _> = sqlx::query_as(
+                "UPDATE media SET status = 'processing' WHERE id = (SELECT id FROM media WHERE status = 'pending' ORDER BY id LIMIT 1) RETURNING id, storage_key, mime"
+            )
+                .fetch_optional(&self.db)
+                .await;
+            let (id, key, mime) = match claimed {
+                Ok(Some(media)) => media,
+                Ok(None) => return,
+                Err(e) => {
+                    println!("{:?}", e);
+                    return;
+                }
+            };
+            if let Err(e) = self.process_image(id, &key, mime).await {
+                println!("Unable to process media {}: {:?}", key, e);
+                if let Err(e) = sqlx::query("UPDATE media SET status = 'failed' WHERE id = $1").bind(id).execute(&self.db).await {
+                    println!("{:?}", e);
+                }
+            }
+        }
+    }
+
+    async fn process_image(&self, id: i64, key: &str, mime: String) -> Result<()> {
+        let data = self.media.store.get(key).await?.ok_or_else(|| anyhow::anyhow!("Missing media file"))?;
+        let image = tokio::task::spawn_blocking(move || process_image(&data, &mime)).await??;
+        self.media.store.put(key, &image.stripped).await?;
+        let mut stored = Vec::new();
+        for variant in &image.variants {
+            let variant_key = Media::generate_key();
+            if let Err(e) = self.media.store.put(&variant_key, &variant.data).await {
+                self.discard_media(&stored).await;
+                return Err(e);
+            }
+            stored.push(variant_key);
+        }
+        match self.finish_processing(id, &image, &stored).await {
+            Ok(true) => Ok(()),
+            // The post was deleted while its image was being processed
+            Ok(false) => {
+                stored.push(key.to_string());
+                self.discard_media(&stored).await;
+                Ok(())
+            },
+            Err(e) => {
+                self.discard_media(&stored).await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn finish_processing(&self, id: i64, image: &ProcessedImage, keys: &[String]) -> Result<bool> {
+        let mut tx = self.db.begin().await?;
+        let updated = sqlx::query("UPDATE media SET status = 'ready', width = $1, height = $2, blurhash = $3, size = $4 WHERE id = $5 AND status = 'processing'")
+            .bind(image.width as i64)
+            .bind(image.height as i64)
+            .bind(&image.blurhash)
+            .bind(image.stripped.len() as i64)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        if updated.rows_affected() == 0 {
+            return Ok(false);
+        }
+        for (variant, key) in image.variants.iter().zip(keys) {
+            sqlx::query("INSERT INTO media_variants (media_id, storage_key, mime, width, height) VALUES ($1, $2, $3, $4, $5)")
+                .bind(id)
+                .bind(key)
+                .bind(variant.mime)
+                .bind(variant.width as i64)
+                .bind(variant.height as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Requeues images left part way through processing by a previous run
+    pub async fn resume_media_processing(&self) -> Result<()> {
+        sqlx::query("UPDATE media SET status = 'pending' WHERE status = 'processing'")
+            .execute(&self.db)
+            .await?;
+        tokio::spawn(self.clone().process_media());
+        Ok(())
+    }
+
+    /// The MIME type and contents of a stored attachment or thumbnail, if its post is visible to the viewer,
+    /// and whether anyone at all may see it
+    pub async fn get_media(&self, key: &str, viewer: Option<i64>) -> Result<Option<(String, Vec<u8>, bool)>> {
+        let query = format!(
+            "SELECT mime, public FROM (
+                SELECT media.storage_key, media.mime, media.post_id FROM media WHERE media.status = 'ready'
+                UNION ALL SELECT media_variants.storage_key, media_variants.mime, media.post_id FROM media_variants INNER JOIN media ON media_variants.media_id = media.id
+            ) AS stored
+            INNER JOIN (
+                SELECT posts.id, posts.visibility IN ('public', 'unlisted') AND users.approve_followers = 0 AS public
+                FROM posts INNER JOIN users ON posts.user_id = users.id
+                WHERE posts.deleted IS NULL AND {}
+            ) AS visible ON stored.post_id = visible.id
+            WHERE stored.storage_key = $1",
+            visible_to("$2")
+        );
+        let mime: Option<(String, i64)> = sqlx::query_as(&query)
+            .bind(key)
+            .bind(viewer)
+            .fetch_optional(&self.db)
+            .await?;
+        match mime {
+            Some((mime, public)) => Ok(self.media.store.get(key).await?.map(|data| (mime, data, public == 1))),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn reblog(&self, user_id: i64, parent: &RawPost, post: &PostDetails) -> Result<i64> {
+        let mut tx = self.db.begin().await?;
+        let (post_id, ): (i64, ) = sqlx::query_as("INSERT INTO posts (user_id, summary, body, format, reply_permission, visibility) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id")
+            .bind(user_id)
+            .bind(post.summary())
+            .bind(&post.body)
+            .bind(post.format.as_str())
+            .bind(post.replies.as_str())
+            .bind(post.visibility.as_str())
+            .fetch_one(&mut *tx)
+            .await?;
+        // A reblog inherits the parent's ancestry one level deeper, plus the parent itself
+        sqlx::query("INSERT INTO post_ancestry (post_id, ancestor_id, depth) SELECT $1, ancestor_id, depth + 1 FROM post_ancestry WHERE post_id = $2 UNION ALL SELECT $1, $2, 1")
+            .bind(post_id)
+            .bind(parent.id)
+            .execute(&mut *tx)
+            .await?;
+        let (parent_author, ): (i64, ) = sqlx::query_as("SELECT user_id FROM posts WHERE id = $1")
+            .bind(parent.id)
+            .fetch_one(&mut *tx)
+            .await?;
+        let mut notified = Vec::new();
+        notified.extend(notify(&mut tx, parent_author, user_id, NotificationKind::Reblog, Some(post_id)).await?);
+        notified.extend(add_contents(&mut tx, post_id, post).await?);
+        tx.commit().await?;
+        self.hub.publish(LiveEvent::Post { post_id, author_id: user_id });
+        self.publish_notifications(notified);
+        Ok(post_id)
+    }
+
+    /// Posts from followed accounts and followed tags, newest first, starting after the `before` post
+    pub async fn get_dash_contents(&self, user_id: i64, before: Option<i64>) -> Result<(Vec<Thread>, bool)> {
+        let query = format!(
+            "SELECT posts.id, users.username, posts.created, posts.summary, posts.body
+            FROM posts INNER JOIN users ON posts.user_id = users.id
+            WHERE posts.deleted IS NULL AND (
+                (posts.user_id IN (SELECT followee FROM follows WHERE follower = $1 AND is_accepted = 1) AND {})
+                OR (
+                    posts.id IN (SELECT postTags.post_id FROM postTags INNER JOIN tagFollows ON tagFollows.tag_id = postTags.tag_id WHERE tagFollows.user_id = $1)
+                    AND posts.visibility = 'public' AND users.approve_followers = 0
+                )
+                OR (posts.visibility = 'mentioned' AND posts.id IN (SELECT post_id FROM mentions WHERE user_id = $1))
+            )
+            AND ($2 IS NULL OR (posts.created, posts.id) < (SELECT created, id FROM posts WHERE id = $2))
+            ORDER BY posts.created DESC, posts.id DESC
+            LIMIT $3",
+            visible_to("$1")
+        );
+        let mut posts: Vec<RawPost> = sqlx::query_as(&query)
+            .bind(user_id)
+            .bind(before)
+            .bind(DASH_PAGE_SIZE + 1)
+            .fetch_all(&self.db)
+            .await?;
+        let has_next = posts.len() as i64 > DASH_PAGE_SIZE;
+        posts.truncate(DASH_PAGE_SIZE as usize);
+        Ok((Thread::from_posts(posts, Some(user_id), &self.db).await?, has_next))
     }
 }
 
+/// Ways of logging in, either of which ends up with the same logged in session
+#[derive(Clone)]
+pub enum Credentials {
+    Password(LoginCredentials),
+    /// An assertion from a passkey, answering the challenge the session was given
+    Passkey { challenge: Challenge, assertion: Assertion },
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error(transparent)]
@@ -103,10 +1255,14 @@ pub enum Error {
 #[async_trait]
 impl AuthnBackend for Backend {
     type User = User;
-    type Credentials = LoginCredentials;
+    type Credentials = Credentials;
     type Error = Error;
 
     async fn authenticate(&self, credentials: Self::Credentials) -> Result<Option<Self::User>, Self::Error> {
+        let credentials = match credentials {
+            Credentials::Password(c) => c,
+            Credentials::Passkey { challenge, assertion } => return self.verify_passkey(&challenge, &assertion).await,
+        };
         let user: Option<Self::User> = sqlx::query_as("SELECT * FROM users WHERE username = $1")
             .bind(credentials.username)
             .fetch_optional(&self.db)
@@ -125,4 +1281,118 @@ impl AuthnBackend for Backend {
     }
 }
 
+/// SQL condition for whether the viewer bound to `viewer` may see `posts`, joined with its author as `users`.
+/// Public and unlisted posts by locked accounts are only visible to accepted followers
+pub(crate) fn visible_to(viewer: &str) -> String {
+    format!(
+        "(posts.user_id = {viewer}
+        OR (posts.visibility IN ('public', 'unlisted') AND (users.approve_followers = 0 OR EXISTS (SELECT 1 FROM follows WHERE follower = {viewer} AND followee = users.id AND is_accepted = 1)))
+        OR (posts.visibility = 'followers' AND EXISTS (SELECT 1 FROM follows WHERE follower = {viewer} AND followee = users.id AND is_accepted = 1))
+        OR (posts.visibility = 'mentioned' AND EXISTS (SELECT 1 FROM mentions WHERE mentions.post_id = posts.id AND mentions.user_id = {viewer})))"
+    )
+}
+
+async fn replace_recovery_codes(conn: &mut AnyConnection, user_id: i64, codes: &[String]) -> Result<()> {
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+    for code in codes {
+        sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) VALUES ($1, $2)")
+            .bind(user_id)
+            .bind(two_factor::hash_recovery_code(code))
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Renders a post's body and replaces its mentions and tags, inline hashtags included.
+/// Returns the users notified of a new mention
+async fn add_contents(conn: &mut AnyConnection, post_id: i64, post: &PostDetails) -> Result<Vec<i64>> {
+    let entities = entities(&post.body, post.format);
+    let mentioned = find_users(conn, entities.mentions).await?;
+    let users = mentioned.keys().cloned().collect();
+    sqlx::query("UPDATE posts SET body_html = $2 WHERE id = $1")
+        .bind(post_id)
+        .bind(render(&post.body, post.format, &users))
+        .execute(&mut *conn)
+        .await?;
+    // Only people newly mentioned by an edit are notified
+    let previous: Vec<(i64, )> = sqlx::query_as("DELETE FROM mentions WHERE post_id = $1 RETURNING user_id")
+        .bind(post_id)
+        .fetch_all(&mut *conn)
+        .await?;
+    let (author_id, ): (i64, ) = sqlx::query_as("SELECT user_id FROM posts WHERE id = $1")
+        .bind(post_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    let mut notified = Vec::new();
+    for user_id in mentioned.values() {
+        sqlx::query("INSERT INTO mentions (post_id, user_id) VALUES ($1, $2)")
+            .bind(post_id)
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await?;
+        if !previous.contains(&(*user_id, )) {
+            notified.extend(notify(conn, *user_id, author_id, NotificationKind::Mention, Some(post_id)).await?);
+        }
+    }
+    let mut tags = post.tags();
+    for tag in entities.hashtags {
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    sqlx::query("DELETE FROM postTags WHERE post_id = $1")
+        .bind(post_id)
+        .execute(&mut *conn)
+        .await?;
+    add_tags(conn, post_id, &tags).await?;
+    Ok(notified)
+}
+
+/// Records a notification, unless it's for the user's own action or they've muted its kind.
+/// Returns the user if they were notified
+async fn notify(conn: &mut AnyConnection, user_id: i64, actor_id: i64, kind: NotificationKind, post_id: Option<i64>) -> Result<Option<i64>> {
+    let inserted = sqlx::query("INSERT INTO notifications (user_id, actor_id, kind, post_id) SELECT $1, $2, $3, $4 WHERE $1 != $2 AND NOT EXISTS (SELECT 1 FROM notification_mutes WHERE user_id = $1 AND kind = $3)")
+        .bind(user_id)
+        .bind(actor_id)
+        .bind(kind.as_str())
+        .bind(post_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok((inserted.rows_affected() > 0).then_some(user_id))
+}
+
+/// Maps the names that belong to existing users to their ids
+async fn find_users(conn: &mut AnyConnection, names: Vec<String>) -> Result<HashMap<String, i64>> {
+    let mut users = HashMap::new();
+    for name in names {
+        let user: Option<(i64, )> = sqlx::query_as("SELECT id FROM users WHERE username = $1")
+            .bind(&name)
+            .fetch_optional(&mut *conn)
+            .await?;
+        if let Some((id, )) = user {
+            users.insert(name, id);
+        }
+    }
+    Ok(users)
+}
+
+async fn add_tags(conn: &mut AnyConnection, post_id: i64, tags: &[String]) -> Result<()> {
+    for tag in tags {
+        sqlx::query("INSERT INTO tags (tag) VALUES ($1) ON CONFLICT (tag) DO NOTHING")
+            .bind(tag)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("INSERT INTO postTags (post_id, tag_id) SELECT $1, id FROM tags WHERE tag = $2 ON CONFLICT DO NOTHING")
+            .bind(post_id)
+            .bind(tag)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
 pub type AuthSession = axum_login::AuthSession<Backend>;
\ No newline at end of file
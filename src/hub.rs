@@ -0,0 +1,44 @@
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+/// How many events a slow subscriber can fall behind before it starts missing them
+const CAPACITY: usize = 256;
+
+#[derive(Clone, Debug)]
+pub enum LiveEvent {
+    /// A post or reblog was made
+    Post { post_id: i64, author_id: i64 },
+    /// A user has a new notification
+    Notification { user_id: i64 },
+    /// Some of a user's sessions were logged out, so their streams should check theirs is still there
+    SessionsRevoked { user_id: i64 },
+    /// The server is stopping, so open streams should end
+    Shutdown,
+}
+
+/// Fans events out to every connected live stream in this process
+#[derive(Clone, Debug)]
+pub struct Hub {
+    sender: Sender<LiveEvent>,
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+
+    pub fn publish(&self, event: LiveEvent) {
+        // Nobody listening isn't an error
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> Receiver<LiveEvent> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,15 @@
+pub mod config;
+pub mod app;
+mod authentication;
+mod routes;
+mod hub;
+mod mail;
+mod media;
+mod model;
+mod param;
+mod passkey;
+mod render;
+mod session;
+mod template;
+mod token;
+mod two_factor;
@@ -1,15 +1,7 @@
 use anyhow::Result;
-use app::App;
+use cotyledon::{app::App, config};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
-mod config;
-mod app;
-mod authentication;
-mod routes;
-mod model;
-mod param;
-mod template;
-
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::registry()
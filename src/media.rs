@@ -0,0 +1,184 @@
+use std::{io::{Cursor, ErrorKind}, path::PathBuf, sync::Arc};
+
+use anyhow::Result;
+use axum::async_trait;
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use rand::{distributions::Alphanumeric, Rng};
+
+pub const MAX_ATTACHMENTS: usize = 4;
+
+/// Widths of the scaled down copies made of each image
+const THUMBNAIL_WIDTHS: [u32; 2] = [320, 960];
+
+/// MIME types accepted for attachments, detected from file contents rather than trusting the client
+const ALLOWED_TYPES: [&str; 9] = [
+    "image/png", "image/jpeg", "image/gif", "image/webp",
+    "video/mp4", "video/webm",
+    "audio/mpeg", "audio/ogg", "audio/x-wav",
+];
+
+/// Storage for uploaded media, addressed by an opaque key
+#[async_trait]
+pub trait MediaStore: Send + Sync {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Keeps media as files in a directory on the local filesystem
+pub struct LocalMediaStore {
+    root: PathBuf,
+}
+
+impl LocalMediaStore {
+    pub async fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        tokio::fs::create_dir_all(&root).await?;
+        Ok(Self { root })
+    }
+
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        // Keys are generated by `Media::generate_key`, anything else can't name a stored file
+        match !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric()) {
+            true => Some(self.root.join(key)),
+            false => None,
+        }
+    }
+}
+
+#[async_trait]
+impl MediaStore for LocalMediaStore {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        match self.path(key) {
+            Some(path) => Ok(tokio::fs::write(path, data).await?),
+            None => Err(anyhow::anyhow!("Invalid media key {}", key)),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = match self.path(key) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        match tokio::fs::read(path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = match self.path(key) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Media {
+    pub store: Arc<dyn MediaStore>,
+    pub max_size: usize,
+}
+
+impl std::fmt::Debug for Media {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Media")
+            .field("max_size", &self.max_size)
+            .finish()
+    }
+}
+
+impl Media {
+    pub fn new(store: impl MediaStore + 'static, max_size: usize) -> Self {
+        Self { store: Arc::new(store), max_size }
+    }
+
+    pub fn generate_key() -> String {
+        rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect()
+    }
+
+    /// The MIME type of an upload, if it's one we accept
+    pub fn sniff(data: &[u8]) -> Option<&'static str> {
+        infer::get(data).map(|t| t.mime_type()).filter(|mime| ALLOWED_TYPES.contains(mime))
+    }
+}
+
+/// A scaled down copy of an image
+pub struct Variant {
+    pub data: Vec<u8>,
+    pub mime: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+pub struct ProcessedImage {
+    /// The original re-encoded without its metadata
+    pub stripped: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: String,
+    pub variants: Vec<Variant>,
+}
+
+/// Strips metadata from an uploaded image, makes its thumbnails and blurhash.
+/// This decodes the whole image, so it belongs on a blocking thread
+pub fn process_image(data: &[u8], mime: &str) -> Result<ProcessedImage> {
+    let format = ImageFormat::from_mime_type(mime).ok_or_else(|| anyhow::anyhow!("Not an image: {}", mime))?;
+    let mut decoder = ImageReader::with_format(Cursor::new(data), format).into_decoder()?;
+    // Rotate the pixels themselves since the EXIF orientation is about to be dropped
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    // Going through `image` would flatten a GIF's animation, so it's rebuilt separately
+    let (stripped, thumbnail_format) = match format {
+        ImageFormat::Gif => (strip_gif(data)?, ImageFormat::Png),
+        format => (encode(&image, format)?, format),
+    };
+    let mut variants = Vec::new();
+    for width in THUMBNAIL_WIDTHS.into_iter().filter(|w| *w < image.width()) {
+        let thumbnail = image.resize(width, u32::MAX, FilterType::Triangle);
+        variants.push(Variant {
+            data: encode(&thumbnail, thumbnail_format)?,
+            mime: thumbnail_format.to_mime_type(),
+            width: thumbnail.width(),
+            height: thumbnail.height(),
+        });
+    }
+    let tiny = image.thumbnail(32, 32).to_rgba8();
+    let blurhash = blurhash::encode(4, 3, tiny.width(), tiny.height(), tiny.as_raw())?;
+    Ok(ProcessedImage { stripped, width: image.width(), height: image.height(), blurhash, variants })
+}
+
+/// Rebuilds a GIF from its frames alone, leaving out comments and application extensions
+/// such as XMP. Frame timing, disposal, transparency and the loop count are kept
+fn strip_gif(data: &[u8]) -> Result<Vec<u8>> {
+    let mut decoder = gif::DecodeOptions::new().read_info(data)?;
+    // The loop count comes before the first frame, so it's known once that's been read
+    let first = decoder.read_next_frame()?.cloned();
+    let mut encoder = gif::Encoder::new(Vec::new(), decoder.width(), decoder.height(), decoder.global_palette().unwrap_or_default())?;
+    if decoder.repeat() != gif::Repeat::Finite(0) {
+        encoder.set_repeat(decoder.repeat())?;
+    }
+    if let Some(frame) = first {
+        encoder.write_frame(&frame)?;
+    }
+    while let Some(frame) = decoder.read_next_frame()? {
+        encoder.write_frame(frame)?;
+    }
+    Ok(encoder.into_inner()?)
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    match format {
+        ImageFormat::Jpeg => image.to_rgb8().write_with_encoder(JpegEncoder::new_with_quality(&mut data, 85))?,
+        format => DynamicImage::from(image.to_rgba8()).write_to(&mut Cursor::new(&mut data), format)?,
+    }
+    Ok(data)
+}
@@ -1,24 +1,18 @@
-use std::{borrow::Cow, fmt::Debug};
+use std::{collections::HashMap, fmt::Debug};
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use sqlx::{AnyPool, FromRow};
 
-#[derive(Clone, Debug, Deserialize, FromRow, Serialize)]
-pub struct User {
-    pub id: i64,
-    pub username: String,
-    pub email: String,
-    pub password: String,
-    pub bio: String,
-}
+use crate::authentication::visible_to;
 
 #[derive(Clone, Deserialize, FromRow, Serialize)]
 pub struct AuthUser {
     pub id: i64,
     pub username: String,
     pub email: String,
-    pub password: String
+    pub password: String,
+    pub email_verified: i64,
 }
 
 impl AuthUser {
@@ -31,8 +25,19 @@ impl AuthUser {
         )
     }
 
-    pub async fn is_following(&self, name: String, db: &AnyPool) -> bool {
-        match sqlx::query("SELECT follower FROM follows WHERE follower = $1 AND followee = (SELECT id FROM users WHERE username = $2)")
+    pub async fn is_following(&self, name: &str, db: &AnyPool) -> bool {
+        match sqlx::query("SELECT follower FROM follows WHERE follower = $1 AND followee = (SELECT id FROM users WHERE username = $2) AND is_accepted = 1")
+            .bind(self.id)
+            .bind(name)
+            .fetch_optional(db)
+            .await {
+            Ok(o) => o.is_some(),
+            Err(_) => false
+        }
+    }
+
+    pub async fn has_requested_follow(&self, name: &str, db: &AnyPool) -> bool {
+        match sqlx::query("SELECT follower FROM follows WHERE follower = $1 AND followee = (SELECT id FROM users WHERE username = $2) AND is_accepted = 0")
             .bind(self.id)
             .bind(name)
             .fetch_optional(db)
@@ -41,6 +46,17 @@ impl AuthUser {
             Err(_) => false
         }
     }
+
+    pub async fn is_following_tag(&self, tag: &str, db: &AnyPool) -> bool {
+        match sqlx::query("SELECT user_id FROM tagFollows WHERE user_id = $1 AND tag_id = (SELECT id FROM tags WHERE tag = $2)")
+            .bind(self.id)
+            .bind(tag)
+            .fetch_optional(db)
+            .await {
+            Ok(o) => o.is_some(),
+            Err(_) => false
+        }
+    }
 }
 
 impl Debug for AuthUser {
@@ -50,6 +66,7 @@ impl Debug for AuthUser {
             .field("username", &self.username)
             .field("email", &self.email)
             .field("password", &"[password]")
+            .field("email_verified", &self.email_verified)
             .finish()
     }
 }
@@ -66,58 +83,15 @@ pub struct DisplayUser {
 pub struct RawPost {
     pub id: i64,
     pub username: String,
-    pub thread: Option<String>,
     pub created: String,
     pub summary: Option<String>,
     pub body: String,
 }
 
 impl RawPost {
-    pub async fn into(self, db: &AnyPool) -> Result<Thread> {
-        let contents = match self.thread {
-            Some(t) => {
-                // Rather hacky here, but should work fine as long as thread data is properly sanitised
-                // Hopefully will be fixable if/when this project migrates to a more cohesive database solution
-                // (probably seaORM or something similar)
-                let args = t.split('/').map(Cow::from).reduce(|mut acc, s| {
-                    acc.to_mut().push_str(", ");
-                    acc.to_mut().push_str(&s);
-                    acc
-                }).unwrap_or_default();
-                let query = format!("SELECT posts.id, users.username, created, summary, body FROM posts INNER JOIN ON post.user_id = user.id WHERE post.id IN ({})", args);
-                println!("Querying: {}", query);
-                let mut result = sqlx::query_as(&query)
-                    .fetch_all(db)
-                    .await?;
-                result.push(Post {
-                    id: self.id,
-                    username: self.username.clone(),
-                    created: self.created.clone(),
-                    summary: self.summary,
-                    body: self.body
-                });
-                result
-            },
-            None => vec![Post {
-                id: self.id,
-                username: self.username.clone(),
-                created: self.created.clone(),
-                summary: self.summary,
-                body: self.body
-            }]
-        };
-        let tags: Vec<(String, )> = sqlx::query_as("SELECT tag FROM tags INNER JOIN postTags ON postTags.tag_id = tags.id WHERE postTags.post_id = $1")
-            .bind(self.id)
-            .fetch_all(db)
-            .await?;
-        Ok(
-            Thread {
-                username: self.username,
-                created: self.created,
-                contents,
-                tags: tags.into_iter().map(|x| x.0).collect()
-            }
-        )
+    pub async fn into(self, viewer: Option<i64>, db: &AnyPool) -> Result<Thread> {
+        let mut threads = Thread::from_posts(vec![self], viewer, db).await?;
+        Ok(threads.remove(0))
     }
 }
 
@@ -126,14 +100,376 @@ pub struct Post {
     pub id: i64,
     pub username: String,
     pub created: String,
+    pub edited: Option<String>,
+    pub deleted: Option<String>,
+    pub summary: Option<String>,
+    pub body: String,
+    pub format: String,
+    pub body_html: String,
+    pub reply_permission: String,
+    pub visibility: String,
+    /// Whether the content warning starts expanded for the viewer
+    #[sqlx(skip)]
+    pub expanded: bool,
+    /// Whether the viewer isn't allowed to see the post, which is then left empty like a deleted one
+    #[sqlx(skip)]
+    pub hidden: bool,
+    #[sqlx(skip)]
+    pub media: Vec<Attachment>,
+}
+
+#[derive(Clone, Debug, Deserialize, FromRow, Serialize)]
+pub struct Attachment {
+    pub id: i64,
+    pub post_id: i64,
+    pub storage_key: String,
+    pub mime: String,
+    pub alt: String,
+    pub status: String,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub blurhash: Option<String>,
+    #[sqlx(skip)]
+    pub variants: Vec<Variant>,
+}
+
+impl Attachment {
+    pub fn kind(&self) -> &str {
+        self.mime.split('/').next().unwrap_or_default()
+    }
+
+    /// Candidate sources for an image, smallest first
+    pub fn srcset(&self) -> String {
+        let mut sources: Vec<String> = self.variants.iter().map(|v| format!("/media/{} {}w", v.storage_key, v.width)).collect();
+        if let Some(width) = self.width {
+            sources.push(format!("/media/{} {}w", self.storage_key, width));
+        }
+        sources.join(", ")
+    }
+}
+
+/// A thumbnail of an image attachment
+#[derive(Clone, Debug, Deserialize, FromRow, Serialize)]
+pub struct Variant {
+    pub media_id: i64,
+    pub storage_key: String,
+    pub width: i64,
+}
+
+#[derive(Debug, Deserialize, FromRow, Serialize)]
+pub struct Reply {
+    pub id: i64,
+    pub username: String,
+    pub created: String,
+    pub body_html: String,
+}
+
+/// A logged in session of a user, as listed on the sessions page
+#[derive(Debug, Deserialize, FromRow, Serialize)]
+pub struct ActiveSession {
+    pub id: String,
+    pub created: Option<String>,
+    pub last_seen: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// A passkey a user can log in with, as listed in their settings
+#[derive(Debug, Deserialize, FromRow, Serialize)]
+pub struct Passkey {
+    pub id: String,
+    pub name: String,
+    pub created: String,
+    pub last_used: Option<String>,
+}
+
+/// A user's two-factor authentication settings, the secret is set but not enabled while enrolling
+#[derive(Debug, FromRow)]
+pub struct TwoFactor {
+    pub totp_secret: Option<String>,
+    pub totp_enabled: i64,
+    pub recovery_codes: i64,
+}
+
+#[derive(Debug, Deserialize, FromRow, Serialize)]
+pub struct Revision {
+    pub id: i64,
+    pub created: String,
     pub summary: Option<String>,
     pub body: String,
 }
 
+/// A like, reblog or reply somewhere in a post's reblog tree
+#[derive(Debug, Deserialize, FromRow, Serialize)]
+pub struct Note {
+    pub kind: String,
+    pub username: String,
+    pub created: String,
+    /// The post liked or replied to, or the reblog itself
+    pub post_id: i64,
+    /// Who a reblog was made from, or who wrote the post replied to
+    pub parent: Option<String>,
+    pub summary: Option<String>,
+    pub body_html: String,
+    #[sqlx(skip)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NotificationKind {
+    Follow,
+    FollowRequest,
+    Like,
+    Reblog,
+    Reply,
+    Mention,
+}
+
+impl NotificationKind {
+    pub const ALL: [NotificationKind; 6] = [
+        NotificationKind::Follow,
+        NotificationKind::FollowRequest,
+        NotificationKind::Like,
+        NotificationKind::Reblog,
+        NotificationKind::Reply,
+        NotificationKind::Mention,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationKind::Follow => "follow",
+            NotificationKind::FollowRequest => "follow_request",
+            NotificationKind::Like => "like",
+            NotificationKind::Reblog => "reblog",
+            NotificationKind::Reply => "reply",
+            NotificationKind::Mention => "mention",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|k| k.as_str() == kind)
+    }
+
+    /// How the kind is described in settings
+    pub fn label(&self) -> &'static str {
+        match self {
+            NotificationKind::Follow => "New followers",
+            NotificationKind::FollowRequest => "Follow requests",
+            NotificationKind::Like => "Likes",
+            NotificationKind::Reblog => "Reblogs",
+            NotificationKind::Reply => "Replies",
+            NotificationKind::Mention => "Mentions",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, FromRow, Serialize)]
+pub struct Notification {
+    pub id: i64,
+    pub kind: String,
+    pub actor: String,
+    pub post_id: Option<i64>,
+    pub post_author: Option<String>,
+    pub is_read: i64,
+    pub created: String,
+}
+
+#[derive(FromRow)]
+struct ChainPost {
+    thread_id: i64,
+    visible: i64,
+    #[sqlx(flatten)]
+    post: Post,
+}
+
 #[derive(Debug, Deserialize, FromRow, Serialize)]
 pub struct Thread {
+    pub id: i64,
     pub username: String,
     pub created: String,
     pub contents: Vec<Post>,
     pub tags: Vec<String>,
-}
\ No newline at end of file
+    /// Likes, reblogs and replies across the whole reblog tree the post belongs to
+    pub notes: i64,
+    /// Replies to this post alone
+    pub replies: i64,
+    /// Whether the viewer has liked this post, see `Thread::mark_liked`
+    pub liked: bool,
+}
+
+impl Thread {
+    /// Assembles threads for many posts at once, preserving their order.
+    /// Posts in a chain that `viewer` can't see are left as placeholders
+    pub async fn from_posts(posts: Vec<RawPost>, viewer: Option<i64>, db: &AnyPool) -> Result<Vec<Thread>> {
+        if posts.is_empty() {
+            return Ok(Vec::new());
+        }
+        // Only the placeholders are generated, every id is still a bound parameter
+        let placeholders = (1..=posts.len()).map(|i| format!("${}", i)).collect::<Vec<_>>().join(", ");
+        // The viewer is bound after the ids
+        let visible = visible_to(&format!("${}", posts.len() + 1));
+        let chain_query = format!(
+            "SELECT chain.thread_id, CASE WHEN {1} THEN 1 ELSE 0 END AS visible, posts.id, users.username, posts.created, posts.edited, posts.deleted, posts.summary, posts.body, posts.format, posts.body_html, posts.reply_permission, posts.visibility
+            FROM (
+                SELECT post_id AS thread_id, ancestor_id AS id, depth FROM post_ancestry WHERE post_id IN ({0})
+                UNION ALL SELECT id, id, 0 FROM posts WHERE id IN ({0})
+            ) AS chain
+            INNER JOIN posts ON posts.id = chain.id
+            INNER JOIN users ON posts.user_id = users.id
+            ORDER BY chain.thread_id, chain.depth DESC",
+            placeholders, visible
+        );
+        let mut query = sqlx::query_as(&chain_query);
+        for post in &posts {
+            query = query.bind(post.id);
+        }
+        let chains: Vec<ChainPost> = query.bind(viewer).fetch_all(db).await?;
+        let tag_query = format!("SELECT postTags.post_id, tag FROM tags INNER JOIN postTags ON postTags.tag_id = tags.id WHERE postTags.post_id IN ({})", placeholders);
+        let mut query = sqlx::query_as(&tag_query);
+        for post in &posts {
+            query = query.bind(post.id);
+        }
+        let tags: Vec<(i64, String)> = query.fetch_all(db).await?;
+        // Notes are shared by every post descended from the same original, counted as `get_notes` lists them
+        let notes_query = format!(
+            "SELECT threads.id,
+                (SELECT COUNT(*) FROM likes WHERE likes.post_id IN ({tree}))
+                + (SELECT COUNT(*) FROM replies WHERE replies.post_id IN ({tree}))
+                + (SELECT COUNT(*) FROM post_ancestry INNER JOIN posts ON posts.id = post_ancestry.post_id INNER JOIN users ON posts.user_id = users.id WHERE post_ancestry.ancestor_id = threads.root AND posts.deleted IS NULL AND {visible}),
+                (SELECT COUNT(*) FROM replies WHERE replies.post_id = threads.id)
+            FROM (
+                SELECT id, COALESCE((SELECT ancestor_id FROM post_ancestry WHERE post_ancestry.post_id = posts.id ORDER BY depth DESC LIMIT 1), id) AS root
+                FROM posts WHERE id IN ({placeholders})
+            ) AS threads",
+            tree = format!(
+                "SELECT posts.id FROM posts INNER JOIN users ON posts.user_id = users.id
+                WHERE (posts.id = threads.root OR posts.id IN (SELECT post_id FROM post_ancestry WHERE ancestor_id = threads.root)) AND posts.deleted IS NULL AND {}",
+                visible
+            ),
+        );
+        let mut query = sqlx::query_as(&notes_query);
+        for post in &posts {
+            query = query.bind(post.id);
+        }
+        let mut notes: HashMap<i64, (i64, i64)> = query.bind(viewer).fetch_all(db).await?.into_iter().map(|(id, notes, replies)| (id, (notes, replies))).collect();
+
+        let mut contents: HashMap<i64, Vec<Post>> = HashMap::new();
+        for mut chain in chains {
+            if chain.visible == 0 {
+                chain.post.hidden = true;
+                chain.post.edited = None;
+                chain.post.summary = None;
+                chain.post.body.clear();
+                chain.post.body_html.clear();
+            }
+            contents.entry(chain.thread_id).or_default().push(chain.post);
+        }
+        let mut thread_tags: HashMap<i64, Vec<String>> = HashMap::new();
+        for (post_id, tag) in tags {
+            thread_tags.entry(post_id).or_default().push(tag);
+        }
+
+        // Attachments for every post in every chain, which may be shared between threads
+        let mut post_ids: Vec<i64> = contents.values().flatten().filter(|p| !p.hidden).map(|p| p.id).collect();
+        post_ids.sort_unstable();
+        post_ids.dedup();
+        if !post_ids.is_empty() {
+            let placeholders = (1..=post_ids.len()).map(|i| format!("${}", i)).collect::<Vec<_>>().join(", ");
+            let media_query = format!(
+                "SELECT id, post_id, storage_key, mime, alt, status, width, height, blurhash FROM media WHERE post_id IN ({}) ORDER BY id",
+                placeholders
+            );
+            let mut query = sqlx::query_as(&media_query);
+            for id in &post_ids {
+                query = query.bind(id);
+            }
+            let mut media: Vec<Attachment> = query.fetch_all(db).await?;
+            let variant_query = format!(
+                "SELECT media_id, media_variants.storage_key, media_variants.width FROM media_variants
+                INNER JOIN media ON media.id = media_variants.media_id
+                WHERE media.post_id IN ({}) ORDER BY media_variants.width",
+                placeholders
+            );
+            let mut query = sqlx::query_as(&variant_query);
+            for id in &post_ids {
+                query = query.bind(id);
+            }
+            let variants: Vec<Variant> = query.fetch_all(db).await?;
+            for attachment in &mut media {
+                attachment.variants = variants.iter().filter(|v| v.media_id == attachment.id).cloned().collect();
+            }
+            for post in contents.values_mut().flatten().filter(|p| !p.hidden) {
+                post.media = media.iter().filter(|m| m.post_id == post.id).cloned().collect();
+            }
+        }
+        Ok(
+            posts.into_iter().map(|post| {
+                let (notes, replies) = notes.remove(&post.id).unwrap_or_default();
+                Thread {
+                    id: post.id,
+                    username: post.username,
+                    created: post.created,
+                    contents: contents.remove(&post.id).unwrap_or_default(),
+                    tags: thread_tags.remove(&post.id).unwrap_or_default(),
+                    notes,
+                    replies,
+                    liked: false,
+                }
+            }).collect()
+        )
+    }
+
+    /// The visibility of the post itself, as opposed to what it reblogs
+    pub fn visibility(&self) -> &str {
+        self.contents.last().map_or("public", |p| p.visibility.as_str())
+    }
+
+    pub async fn mark_liked(threads: &mut [Thread], viewer: Option<i64>, db: &AnyPool) -> Result<()> {
+        let viewer = match viewer {
+            Some(v) if !threads.is_empty() => v,
+            _ => return Ok(()),
+        };
+        let like_query = format!(
+            "SELECT post_id FROM likes WHERE user_id = $1 AND post_id IN ({})",
+            (2..=threads.len() + 1).map(|i| format!("${}", i)).collect::<Vec<_>>().join(", ")
+        );
+        let mut query = sqlx::query_as(&like_query).bind(viewer);
+        for thread in threads.iter() {
+            query = query.bind(thread.id);
+        }
+        let liked: Vec<(i64, )> = query.fetch_all(db).await?;
+        for thread in threads {
+            thread.liked = liked.iter().any(|(id, )| *id == thread.id);
+        }
+        Ok(())
+    }
+}
+
+/// A viewer's content warning preferences
+#[derive(Clone, Debug, Default)]
+pub struct ContentFilter {
+    pub expand: bool,
+    pub hidden_words: Vec<String>,
+}
+
+impl ContentFilter {
+    pub fn new(expand: bool, hidden_words: &str) -> Self {
+        Self {
+            expand,
+            hidden_words: hidden_words.split(',').map(|w| w.trim().to_lowercase()).filter(|w| !w.is_empty()).collect(),
+        }
+    }
+
+    /// Expands content warnings unless they mention one of the hidden words
+    pub fn apply(&self, threads: &mut [Thread]) {
+        for post in threads.iter_mut().flat_map(|t| t.contents.iter_mut()) {
+            post.expanded = match &post.summary {
+                Some(summary) => {
+                    let summary = summary.to_lowercase();
+                    self.expand && !self.hidden_words.iter().any(|w| summary.contains(w))
+                },
+                None => true,
+            };
+        }
+    }
+}
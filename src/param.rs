@@ -1,5 +1,9 @@
+use std::collections::HashMap;
+
 use serde::Deserialize;
 
+use crate::{model::NotificationKind, passkey::{Assertion, Registration}, render::Format};
+
 #[derive(Clone, Deserialize)]
 pub struct LoginCredentials {
     pub username: String, 
@@ -25,18 +29,205 @@ pub struct RegisterCredentials {
     pub next: Option<String>,
 }
 
+#[derive(Deserialize)]
+pub struct PasswordDetails {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordDetails {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordDetails {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenQuery {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorCode {
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct DisableTwoFactorDetails {
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasskeyLoginDetails {
+    #[serde(flatten)]
+    pub assertion: Assertion,
+    pub next: Option<String>,
+}
+
+/// The current password, asked for before a passkey can be added
+#[derive(Deserialize)]
+pub struct PasskeyOptionsDetails {
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct AddPasskeyDetails {
+    #[serde(flatten)]
+    pub registration: Registration,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasskeyDetails {
+    pub id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SessionDetails {
+    pub id: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct NextUrl {
     pub next: Option<String>,
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Debug, Deserialize)]
+pub struct Page {
+    pub page: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Cursor {
+    pub before: Option<i64>,
+}
+
+#[derive(Clone, Default, Deserialize)]
 pub struct PostDetails {
+    #[serde(default)]
+    pub summary: String,
+    pub body: String,
+    #[serde(default)]
+    pub format: Format,
+    #[serde(default)]
+    pub tags: String,
+    #[serde(default)]
+    pub replies: ReplyPermission,
+    #[serde(default)]
+    pub visibility: Visibility,
+}
+
+impl PostDetails {
+    /// The content warning, if one was given
+    pub fn summary(&self) -> Option<&str> {
+        Some(self.summary.trim()).filter(|s| !s.is_empty())
+    }
+
+    /// Comma separated tags, normalised and deduplicated
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags: Vec<String> = Vec::new();
+        for tag in self.tags.split(',') {
+            let tag = normalise_tag(tag);
+            if !tag.is_empty() && !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        tags
+    }
+}
+
+/// Who besides the author can reply to a post
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplyPermission {
+    #[default]
+    Everyone,
+    Followers,
+    Nobody,
+}
+
+impl ReplyPermission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReplyPermission::Everyone => "everyone",
+            ReplyPermission::Followers => "followers",
+            ReplyPermission::Nobody => "nobody",
+        }
+    }
+}
+
+/// Who can see a post. Mentioned-only posts are visible to the users mentioned in them
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Public,
+    Unlisted,
+    Followers,
+    Mentioned,
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Unlisted => "unlisted",
+            Visibility::Followers => "followers",
+            Visibility::Mentioned => "mentioned",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplyDetails {
     pub body: String,
 }
 
+/// Trims whitespace and any leading `#`, collapses inner whitespace and lowercases
+pub fn normalise_tag(tag: &str) -> String {
+    tag.trim().trim_start_matches('#').split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// An attachment accepted from the compose form
+pub struct Upload {
+    pub data: Vec<u8>,
+    pub mime: &'static str,
+    pub alt: String,
+}
+
 #[derive(Clone, Deserialize)]
 pub struct FollowDetails {
     pub name: String,
     pub id: i64,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct TagFollowDetails {
+    pub tag: String,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct FollowRequestDetails {
+    pub id: i64,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct SettingsDetails {
+    pub approve_followers: Option<String>,
+    pub cw_expand: Option<String>,
+    #[serde(default)]
+    pub cw_hidden_words: String,
+    /// Checkboxes named `mute_<kind>` for each muted kind of notification
+    #[serde(flatten)]
+    pub mutes: HashMap<String, String>,
+}
+
+impl SettingsDetails {
+    pub fn muted(&self) -> Vec<NotificationKind> {
+        NotificationKind::ALL.into_iter().filter(|k| self.mutes.contains_key(&format!("mute_{}", k.as_str()))).collect()
+    }
 }
\ No newline at end of file
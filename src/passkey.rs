@@ -0,0 +1,488 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::Value;
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tower_sessions::cookie::time::{Duration, OffsetDateTime};
+use url::Url;
+
+const RP_NAME: &str = "Cotyledon";
+/// COSE identifier for ECDSA over P-256 with SHA-256, which every passkey provider supports
+const ES256: i64 = -7;
+/// How long the browser has to answer a challenge
+const CHALLENGE_LIFETIME: Duration = Duration::minutes(5);
+/// Session data key for the challenge of a passkey being added
+pub const REGISTRATION_KEY: &str = "passkey.registration";
+/// Session data key for the challenge of a passkey login
+pub const AUTHENTICATION_KEY: &str = "passkey.authentication";
+
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+/// Who passkeys are created for, taken from the address the site is reachable at.
+/// Browsers only hand out a passkey to the origin it was created on
+#[derive(Clone, Debug)]
+pub struct RelyingParty {
+    pub id: String,
+    pub origin: String,
+}
+
+/// A challenge handed to the browser, kept in the session until it's answered
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Challenge {
+    pub value: String,
+    pub expires: i64,
+}
+
+impl Challenge {
+    pub fn new() -> Self {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        Self {
+            value: URL_SAFE_NO_PAD.encode(bytes),
+            expires: (OffsetDateTime::now_utc() + CHALLENGE_LIFETIME).unix_timestamp(),
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires <= OffsetDateTime::now_utc().unix_timestamp()
+    }
+}
+
+/// What `navigator.credentials.create` gave back, binary fields base64url encoded
+#[derive(Clone, Debug, Deserialize)]
+pub struct Registration {
+    pub id: String,
+    pub client_data: String,
+    pub attestation_object: String,
+    pub name: String,
+}
+
+/// What `navigator.credentials.get` gave back, binary fields base64url encoded
+#[derive(Clone, Debug, Deserialize)]
+pub struct Assertion {
+    pub id: String,
+    pub client_data: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+/// A verified passkey ready to be stored, the public key SEC1 encoded then base64url encoded
+pub struct NewPasskey {
+    pub id: String,
+    pub public_key: String,
+    pub sign_count: i64,
+}
+
+/// The opaque id passkeys are created with for a user, handed back when logging in with one
+pub fn user_handle(user_id: i64) -> String {
+    URL_SAFE_NO_PAD.encode(user_id.to_be_bytes())
+}
+
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    /// Attested credential data and extensions, if there are any
+    rest: &'a [u8],
+}
+
+impl<'a> AuthenticatorData<'a> {
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < 37 {
+            return None;
+        }
+        Some(Self {
+            rp_id_hash: &bytes[..32],
+            flags: bytes[32],
+            sign_count: u32::from_be_bytes(bytes[33..37].try_into().ok()?),
+            rest: &bytes[37..],
+        })
+    }
+}
+
+impl RelyingParty {
+    pub fn new(base_url: &str) -> anyhow::Result<Self> {
+        let url = Url::parse(base_url)?;
+        let id = url.host_str().ok_or_else(|| anyhow::anyhow!("The base URL needs a host for passkeys"))?;
+        Ok(Self { id: id.to_string(), origin: url.origin().ascii_serialization() })
+    }
+
+    /// Options for `navigator.credentials.create`, `existing` being the ids of the user's passkeys
+    pub fn creation_options(&self, challenge: &Challenge, user_id: i64, username: &str, existing: &[String]) -> serde_json::Value {
+        json!({
+            "challenge": challenge.value,
+            "rp": { "id": self.id, "name": RP_NAME },
+            "user": { "id": user_handle(user_id), "name": username, "displayName": username },
+            "pubKeyCredParams": [{ "type": "public-key", "alg": ES256 }],
+            "excludeCredentials": existing.iter().map(|id| json!({ "type": "public-key", "id": id })).collect::<Vec<_>>(),
+            "authenticatorSelection": { "residentKey": "required", "userVerification": "required" },
+            "attestation": "none",
+            "timeout": CHALLENGE_LIFETIME.whole_milliseconds() as i64,
+        })
+    }
+
+    /// Options for `navigator.credentials.get`. Passkeys are discoverable so none are listed,
+    /// the browser offers whichever it has for the site
+    pub fn request_options(&self, challenge: &Challenge) -> serde_json::Value {
+        json!({
+            "challenge": challenge.value,
+            "rpId": self.id,
+            "userVerification": "required",
+            "timeout": CHALLENGE_LIFETIME.whole_milliseconds() as i64,
+        })
+    }
+
+    /// Checks a new passkey answers `challenge` and was made for this site.
+    /// Attestation isn't asked for, so who made the authenticator isn't checked
+    pub fn verify_registration(&self, challenge: &Challenge, registration: &Registration) -> Option<NewPasskey> {
+        let client_data = URL_SAFE_NO_PAD.decode(&registration.client_data).ok()?;
+        if !self.check_client_data(&client_data, "webauthn.create", challenge) {
+            return None;
+        }
+        let attestation: Value = ciborium::from_reader(URL_SAFE_NO_PAD.decode(&registration.attestation_object).ok()?.as_slice()).ok()?;
+        let auth_data = map_get(&attestation, Value::Text(String::from("authData")))?.as_bytes()?;
+        let auth_data = self.check_authenticator_data(auth_data)?;
+        if auth_data.flags & ATTESTED_CREDENTIAL_DATA == 0 || auth_data.rest.len() < 18 {
+            return None;
+        }
+        // Attested credential data is a 16 byte AAGUID, the length of the credential id, the id and its COSE key
+        let id_length = u16::from_be_bytes(auth_data.rest[16..18].try_into().ok()?) as usize;
+        let credential_id = auth_data.rest.get(18..18 + id_length)?;
+        if URL_SAFE_NO_PAD.decode(&registration.id).ok()? != credential_id {
+            return None;
+        }
+        let mut cose_key = &auth_data.rest[18 + id_length..];
+        let cose_key: Value = ciborium::from_reader(&mut cose_key).ok()?;
+        Some(NewPasskey {
+            id: URL_SAFE_NO_PAD.encode(credential_id),
+            public_key: URL_SAFE_NO_PAD.encode(es256_public_key(&cose_key)?),
+            sign_count: auth_data.sign_count as i64,
+        })
+    }
+
+    /// Checks an assertion answers `challenge` and is signed by the stored passkey.
+    /// Returns the passkey's new signature counter
+    pub fn verify_assertion(&self, challenge: &Challenge, assertion: &Assertion, public_key: &str, sign_count: i64) -> Option<i64> {
+        let client_data = URL_SAFE_NO_PAD.decode(&assertion.client_data).ok()?;
+        if !self.check_client_data(&client_data, "webauthn.get", challenge) {
+            return None;
+        }
+        let auth_data_bytes = URL_SAFE_NO_PAD.decode(&assertion.authenticator_data).ok()?;
+        let auth_data = self.check_authenticator_data(&auth_data_bytes)?;
+        let key = VerifyingKey::from_sec1_bytes(&URL_SAFE_NO_PAD.decode(public_key).ok()?).ok()?;
+        let signature = Signature::from_der(&URL_SAFE_NO_PAD.decode(&assertion.signature).ok()?).ok()?;
+        let mut signed = auth_data_bytes.clone();
+        signed.extend_from_slice(&Sha256::digest(&client_data));
+        key.verify(&signed, &signature).ok()?;
+        // Counters that don't go up suggest the passkey was cloned, some providers always send 0
+        let new_count = auth_data.sign_count as i64;
+        if (new_count != 0 || sign_count != 0) && new_count <= sign_count {
+            return None;
+        }
+        Some(new_count)
+    }
+
+    fn check_client_data(&self, client_data: &[u8], kind: &str, challenge: &Challenge) -> bool {
+        let client_data: serde_json::Value = match serde_json::from_slice(client_data) {
+            Ok(c) => c,
+            Err(_) => return false,
+        };
+        !challenge.is_expired()
+            && client_data["type"] == kind
+            && client_data["challenge"] == challenge.value.as_str()
+            && client_data["origin"] == self.origin.as_str()
+    }
+
+    /// Passkeys have to be for this site and the user has to have unlocked them, with a PIN or biometrics
+    fn check_authenticator_data<'a>(&self, bytes: &'a [u8]) -> Option<AuthenticatorData<'a>> {
+        let auth_data = AuthenticatorData::parse(bytes)?;
+        let verified = USER_PRESENT | USER_VERIFIED;
+        match auth_data.rp_id_hash == Sha256::digest(self.id.as_bytes()).as_slice() && auth_data.flags & verified == verified {
+            true => Some(auth_data),
+            false => None,
+        }
+    }
+}
+
+fn map_get(map: &Value, key: Value) -> Option<&Value> {
+    map.as_map()?.iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+}
+
+/// The SEC1 encoding of an ES256 COSE key, validated as a point on the curve
+fn es256_public_key(cose_key: &Value) -> Option<Vec<u8>> {
+    let int = |label: i64| map_get(cose_key, Value::Integer(label.into()));
+    let is = |label: i64, expected: i64| int(label).and_then(|v| v.as_integer()).is_some_and(|v| i128::from(v) == expected as i128);
+    // kty EC2, alg ES256 and crv P-256
+    if !(is(1, 2) && is(3, ES256) && is(-1, 1)) {
+        return None;
+    }
+    let mut key = vec![0x04];
+    key.extend_from_slice(int(-2)?.as_bytes()?);
+    key.extend_from_slice(int(-3)?.as_bytes()?);
+    VerifyingKey::from_sec1_bytes(&key).ok()?;
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use p256::ecdsa::{signature::Signer, SigningKey};
+
+    use super::*;
+
+    const ORIGIN: &str = "http://localhost:3000";
+
+    /// A passkey provider in software, holding one ES256 passkey
+    struct Authenticator {
+        key: SigningKey,
+        id: Vec<u8>,
+        sign_count: u32,
+        /// Whether the counter goes up with each use, some providers leave it at 0
+        counts: bool,
+    }
+
+    impl Authenticator {
+        fn new() -> Self {
+            let mut id = vec![0u8; 16];
+            OsRng.fill_bytes(&mut id);
+            Self { key: SigningKey::random(&mut OsRng), id, sign_count: 0, counts: true }
+        }
+
+        fn cose_key(&self) -> Value {
+            let point = self.key.verifying_key().to_encoded_point(false);
+            cose_key(2, ES256, 1, point.x().unwrap(), point.y().unwrap())
+        }
+
+        fn register(&self, challenge: &Challenge, origin: &str, rp_id: &str) -> Registration {
+            let mut attested = vec![0u8; 16];
+            attested.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+            attested.extend_from_slice(&self.id);
+            ciborium::into_writer(&self.cose_key(), &mut attested).unwrap();
+            let auth_data = authenticator_data(rp_id, USER_PRESENT | USER_VERIFIED | ATTESTED_CREDENTIAL_DATA, self.sign_count, &attested);
+            let attestation = Value::Map(vec![
+                (Value::Text(String::from("fmt")), Value::Text(String::from("none"))),
+                (Value::Text(String::from("attStmt")), Value::Map(Vec::new())),
+                (Value::Text(String::from("authData")), Value::Bytes(auth_data)),
+            ]);
+            let mut attestation_object = Vec::new();
+            ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+            Registration {
+                id: URL_SAFE_NO_PAD.encode(&self.id),
+                client_data: URL_SAFE_NO_PAD.encode(client_data("webauthn.create", challenge, origin)),
+                attestation_object: URL_SAFE_NO_PAD.encode(attestation_object),
+                name: String::from("Test"),
+            }
+        }
+
+        fn assert(&mut self, challenge: &Challenge, origin: &str, rp_id: &str) -> Assertion {
+            if self.counts {
+                self.sign_count += 1;
+            }
+            let auth_data = authenticator_data(rp_id, USER_PRESENT | USER_VERIFIED, self.sign_count, &[]);
+            let client_data = client_data("webauthn.get", challenge, origin);
+            let mut signed = auth_data.clone();
+            signed.extend_from_slice(&Sha256::digest(&client_data));
+            let signature: Signature = self.key.sign(&signed);
+            Assertion {
+                id: URL_SAFE_NO_PAD.encode(&self.id),
+                client_data: URL_SAFE_NO_PAD.encode(client_data),
+                authenticator_data: URL_SAFE_NO_PAD.encode(auth_data),
+                signature: URL_SAFE_NO_PAD.encode(signature.to_der().as_bytes()),
+                user_handle: None,
+            }
+        }
+    }
+
+    fn cose_key(kty: i64, alg: i64, crv: i64, x: &[u8], y: &[u8]) -> Value {
+        Value::Map(vec![
+            (Value::Integer(1.into()), Value::Integer(kty.into())),
+            (Value::Integer(3.into()), Value::Integer(alg.into())),
+            (Value::Integer((-1).into()), Value::Integer(crv.into())),
+            (Value::Integer((-2).into()), Value::Bytes(x.to_vec())),
+            (Value::Integer((-3).into()), Value::Bytes(y.to_vec())),
+        ])
+    }
+
+    fn client_data(kind: &str, challenge: &Challenge, origin: &str) -> Vec<u8> {
+        json!({ "type": kind, "challenge": challenge.value, "origin": origin }).to_string().into_bytes()
+    }
+
+    fn authenticator_data(rp_id: &str, flags: u8, sign_count: u32, rest: &[u8]) -> Vec<u8> {
+        let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend_from_slice(&sign_count.to_be_bytes());
+        data.extend_from_slice(rest);
+        data
+    }
+
+    fn relying_party() -> RelyingParty {
+        RelyingParty::new(ORIGIN).unwrap()
+    }
+
+    fn expired() -> Challenge {
+        Challenge { expires: OffsetDateTime::now_utc().unix_timestamp() - 1, ..Challenge::new() }
+    }
+
+    /// A registered authenticator and the passkey stored for it
+    fn registered() -> (Authenticator, NewPasskey) {
+        let authenticator = Authenticator::new();
+        let challenge = Challenge::new();
+        let passkey = relying_party().verify_registration(&challenge, &authenticator.register(&challenge, ORIGIN, "localhost")).unwrap();
+        (authenticator, passkey)
+    }
+
+    #[test]
+    fn relying_party_from_base_url() {
+        let rp = RelyingParty::new("https://example.com:8443/some/path").unwrap();
+        assert_eq!(rp.id, "example.com");
+        assert_eq!(rp.origin, "https://example.com:8443");
+    }
+
+    #[test]
+    fn registration_gives_the_passkey() {
+        let (authenticator, passkey) = registered();
+        assert_eq!(passkey.id, URL_SAFE_NO_PAD.encode(&authenticator.id));
+        assert_eq!(URL_SAFE_NO_PAD.decode(passkey.public_key).unwrap(), authenticator.key.verifying_key().to_encoded_point(false).as_bytes());
+        assert_eq!(passkey.sign_count, 0);
+    }
+
+    #[test]
+    fn registration_checks_origin_and_rp_id() {
+        let authenticator = Authenticator::new();
+        let challenge = Challenge::new();
+        let rp = relying_party();
+        assert!(rp.verify_registration(&challenge, &authenticator.register(&challenge, "http://evil.example", "localhost")).is_none());
+        assert!(rp.verify_registration(&challenge, &authenticator.register(&challenge, ORIGIN, "evil.example")).is_none());
+    }
+
+    #[test]
+    fn registration_checks_challenge() {
+        let authenticator = Authenticator::new();
+        let rp = relying_party();
+        let answered = Challenge::new();
+        assert!(rp.verify_registration(&Challenge::new(), &authenticator.register(&answered, ORIGIN, "localhost")).is_none());
+        let expired = expired();
+        assert!(rp.verify_registration(&expired, &authenticator.register(&expired, ORIGIN, "localhost")).is_none());
+    }
+
+    #[test]
+    fn registration_checks_credential_id() {
+        let authenticator = Authenticator::new();
+        let challenge = Challenge::new();
+        let mut registration = authenticator.register(&challenge, ORIGIN, "localhost");
+        registration.id = URL_SAFE_NO_PAD.encode([1u8; 16]);
+        assert!(relying_party().verify_registration(&challenge, &registration).is_none());
+    }
+
+    #[test]
+    fn registration_needs_user_verification() {
+        let authenticator = Authenticator::new();
+        let challenge = Challenge::new();
+        let mut registration = authenticator.register(&challenge, ORIGIN, "localhost");
+        let attestation: Value = ciborium::from_reader(URL_SAFE_NO_PAD.decode(&registration.attestation_object).unwrap().as_slice()).unwrap();
+        let mut auth_data = map_get(&attestation, Value::Text(String::from("authData"))).unwrap().as_bytes().unwrap().clone();
+        auth_data[32] &= !USER_VERIFIED;
+        let attestation = Value::Map(vec![(Value::Text(String::from("authData")), Value::Bytes(auth_data))]);
+        let mut attestation_object = Vec::new();
+        ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+        registration.attestation_object = URL_SAFE_NO_PAD.encode(attestation_object);
+        assert!(relying_party().verify_registration(&challenge, &registration).is_none());
+    }
+
+    #[test]
+    fn registration_is_not_an_assertion() {
+        let (mut authenticator, _) = registered();
+        let challenge = Challenge::new();
+        let assertion = authenticator.assert(&challenge, ORIGIN, "localhost");
+        let mut registration = authenticator.register(&challenge, ORIGIN, "localhost");
+        registration.client_data = assertion.client_data;
+        assert!(relying_party().verify_registration(&challenge, &registration).is_none());
+    }
+
+    #[test]
+    fn assertion_gives_the_new_count() {
+        let (mut authenticator, passkey) = registered();
+        let challenge = Challenge::new();
+        let assertion = authenticator.assert(&challenge, ORIGIN, "localhost");
+        assert_eq!(relying_party().verify_assertion(&challenge, &assertion, &passkey.public_key, passkey.sign_count), Some(1));
+    }
+
+    #[test]
+    fn assertion_checks_origin_and_rp_id() {
+        let (mut authenticator, passkey) = registered();
+        let challenge = Challenge::new();
+        let rp = relying_party();
+        let assertion = authenticator.assert(&challenge, "http://evil.example", "localhost");
+        assert!(rp.verify_assertion(&challenge, &assertion, &passkey.public_key, 0).is_none());
+        let assertion = authenticator.assert(&challenge, ORIGIN, "evil.example");
+        assert!(rp.verify_assertion(&challenge, &assertion, &passkey.public_key, 0).is_none());
+    }
+
+    #[test]
+    fn assertion_checks_challenge() {
+        let (mut authenticator, passkey) = registered();
+        let rp = relying_party();
+        let assertion = authenticator.assert(&Challenge::new(), ORIGIN, "localhost");
+        assert!(rp.verify_assertion(&Challenge::new(), &assertion, &passkey.public_key, 0).is_none());
+        let expired = expired();
+        let assertion = authenticator.assert(&expired, ORIGIN, "localhost");
+        assert!(rp.verify_assertion(&expired, &assertion, &passkey.public_key, 0).is_none());
+    }
+
+    #[test]
+    fn assertion_checks_signature() {
+        let (mut authenticator, passkey) = registered();
+        let challenge = Challenge::new();
+        let rp = relying_party();
+        let (_, other) = registered();
+        let assertion = authenticator.assert(&challenge, ORIGIN, "localhost");
+        assert!(rp.verify_assertion(&challenge, &assertion, &other.public_key, 0).is_none());
+        // Signed over different client data
+        let mut tampered = authenticator.assert(&challenge, ORIGIN, "localhost");
+        tampered.client_data = URL_SAFE_NO_PAD.encode(format!("{} ", String::from_utf8(client_data("webauthn.get", &challenge, ORIGIN)).unwrap()));
+        assert!(rp.verify_assertion(&challenge, &tampered, &passkey.public_key, 0).is_none());
+    }
+
+    #[test]
+    fn assertion_counter_has_to_go_up() {
+        let (mut authenticator, passkey) = registered();
+        let challenge = Challenge::new();
+        let rp = relying_party();
+        let assertion = authenticator.assert(&challenge, ORIGIN, "localhost");
+        assert!(rp.verify_assertion(&challenge, &assertion, &passkey.public_key, 1).is_none());
+        assert!(rp.verify_assertion(&challenge, &assertion, &passkey.public_key, 5).is_none());
+        // Providers that don't keep a counter always send 0
+        authenticator.counts = false;
+        authenticator.sign_count = 0;
+        let assertion = authenticator.assert(&challenge, ORIGIN, "localhost");
+        assert_eq!(rp.verify_assertion(&challenge, &assertion, &passkey.public_key, 0), Some(0));
+        assert!(rp.verify_assertion(&challenge, &assertion, &passkey.public_key, 3).is_none());
+    }
+
+    #[test]
+    fn es256_public_key_is_sec1() {
+        let authenticator = Authenticator::new();
+        let key = es256_public_key(&authenticator.cose_key()).unwrap();
+        assert_eq!(key.len(), 65);
+        assert_eq!(key, authenticator.key.verifying_key().to_encoded_point(false).as_bytes());
+    }
+
+    #[test]
+    fn es256_public_key_rejects_other_keys() {
+        let point = Authenticator::new().key.verifying_key().to_encoded_point(false);
+        let (x, y) = (point.x().unwrap().as_slice(), point.y().unwrap().as_slice());
+        // OKP, EdDSA, P-384
+        assert!(es256_public_key(&cose_key(1, ES256, 1, x, y)).is_none());
+        assert!(es256_public_key(&cose_key(2, -8, 1, x, y)).is_none());
+        assert!(es256_public_key(&cose_key(2, ES256, 2, x, y)).is_none());
+        // Not on the curve, or a coordinate missing
+        assert!(es256_public_key(&cose_key(2, ES256, 1, x, x)).is_none());
+        assert!(es256_public_key(&cose_key(2, ES256, 1, x, &[])).is_none());
+        assert!(es256_public_key(&Value::Map(Vec::new())).is_none());
+        assert!(es256_public_key(&Value::Integer(1.into())).is_none());
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::HashSet;
+
+use ammonia::Builder;
+use pulldown_cmark::{html, Event, Options, Parser, Tag, TagEnd, TextMergeStream};
+use serde::{Deserialize, Serialize};
+
+use crate::param::normalise_tag;
+
+/// How a post body is written
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Plain,
+    Markdown,
+}
+
+impl Format {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::Plain => "plain",
+            Format::Markdown => "markdown",
+        }
+    }
+}
+
+/// Mentions and hashtags written inline in a post body
+#[derive(Debug, Default)]
+pub struct Entities {
+    pub mentions: Vec<String>,
+    pub hashtags: Vec<String>,
+}
+
+/// Finds the `@username` mentions and `#hashtags` in a body, ignoring code and existing links
+pub fn entities(body: &str, format: Format) -> Entities {
+    let mut entities = Entities::default();
+    let mut add = |text: &str| {
+        for token in tokenise(text) {
+            match token {
+                Token::Mention(name) if !entities.mentions.iter().any(|m| m == name) => entities.mentions.push(name.to_string()),
+                Token::Hashtag(tag) => {
+                    let tag = normalise_tag(tag);
+                    if !entities.hashtags.contains(&tag) {
+                        entities.hashtags.push(tag);
+                    }
+                },
+                _ => (),
+            }
+        }
+    };
+    match format {
+        Format::Plain => add(body),
+        Format::Markdown => {
+            let mut verbatim = 0;
+            for event in TextMergeStream::new(Parser::new_ext(body, Options::ENABLE_STRIKETHROUGH)) {
+                match event {
+                    Event::Start(Tag::CodeBlock(_) | Tag::Link { .. } | Tag::Image { .. }) => verbatim += 1,
+                    Event::End(TagEnd::CodeBlock | TagEnd::Link | TagEnd::Image) => verbatim -= 1,
+                    Event::Text(text) if verbatim == 0 => add(&text),
+                    _ => (),
+                }
+            }
+        },
+    }
+    entities
+}
+
+/// Renders a post body to sanitised HTML, ready to be stored alongside the source.
+/// Mentions are only linked for names in `users`
+pub fn render(body: &str, format: Format, users: &HashSet<String>) -> String {
+    let html = match format {
+        Format::Plain => plain(body, users),
+        Format::Markdown => markdown(body, users),
+    };
+    sanitise(&html)
+}
+
+fn plain(body: &str, users: &HashSet<String>) -> String {
+    body.replace("\r\n", "\n")
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| format!("<p>{}</p>", linkify(p, users).replace('\n', "<br/>")))
+        .collect()
+}
+
+fn markdown(body: &str, users: &HashSet<String>) -> String {
+    let mut verbatim = 0;
+    let events = TextMergeStream::new(Parser::new_ext(body, Options::ENABLE_STRIKETHROUGH)).map(|event| match event {
+        Event::Start(Tag::CodeBlock(_) | Tag::Link { .. } | Tag::Image { .. }) => {
+            verbatim += 1;
+            event
+        },
+        Event::End(TagEnd::CodeBlock | TagEnd::Link | TagEnd::Image) => {
+            verbatim -= 1;
+            event
+        },
+        Event::Text(text) if verbatim == 0 => Event::InlineHtml(linkify(&text, users).into()),
+        event => event,
+    });
+    let mut html = String::new();
+    html::push_html(&mut html, events);
+    html
+}
+
+/// Escapes text, turning mentions, hashtags and bare URLs into links
+fn linkify(text: &str, users: &HashSet<String>) -> String {
+    let mut html = String::with_capacity(text.len());
+    for token in tokenise(text) {
+        match token {
+            Token::Text(text) => html.push_str(&escape(text)),
+            Token::Mention(name) if users.contains(name) => {
+                html.push_str(&format!("<a href=\"/user/{}\">@{}</a>", urlencoding::encode(name), escape(name)));
+            },
+            Token::Mention(name) => {
+                html.push('@');
+                html.push_str(&escape(name));
+            },
+            Token::Hashtag(tag) => {
+                html.push_str(&format!("<a href=\"/tag/{}\">#{}</a>", urlencoding::encode(&normalise_tag(tag)), escape(tag)));
+            },
+            Token::Url(url) => {
+                let url = escape(url);
+                html.push_str(&format!("<a href=\"{}\">{}</a>", url, url));
+            },
+        }
+    }
+    html
+}
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Text(&'a str),
+    Mention(&'a str),
+    Hashtag(&'a str),
+    Url(&'a str),
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn tokenise(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        let boundary = !text[..i].chars().next_back().is_some_and(is_name_char);
+        let found = if !boundary {
+            None
+        } else if let Some(name) = rest.strip_prefix('@') {
+            let len = name.find(|c| !is_name_char(c)).unwrap_or(name.len());
+            (len > 0).then(|| (Token::Mention(&name[..len]), len + 1))
+        } else if let Some(tag) = rest.strip_prefix('#') {
+            let len = tag.find(|c| !is_name_char(c) && c != '-').unwrap_or(tag.len());
+            (len > 0).then(|| (Token::Hashtag(&tag[..len]), len + 1))
+        } else if rest.starts_with("https://") || rest.starts_with("http://") {
+            let len = rest.find(|c: char| c.is_whitespace() || c == '<' || c == '>' || c == '"').unwrap_or(rest.len());
+            // Trailing punctuation usually belongs to the surrounding sentence
+            let len = rest[..len].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '\'']).len();
+            (!rest[..len].ends_with("://")).then(|| (Token::Url(&rest[..len]), len))
+        } else {
+            None
+        };
+        match found {
+            Some((token, len)) => {
+                if start < i {
+                    tokens.push(Token::Text(&text[start..i]));
+                }
+                tokens.push(token);
+                i += len;
+                start = i;
+            },
+            None => i += rest.chars().next().map_or(1, char::len_utf8),
+        }
+    }
+    if start < text.len() {
+        tokens.push(Token::Text(&text[start..]));
+    }
+    tokens
+}
+
+fn sanitise(html: &str) -> String {
+    Builder::empty()
+        .tags(HashSet::from([
+            "a", "blockquote", "br", "code", "del", "em", "h1", "h2", "h3", "h4", "h5", "h6",
+            "hr", "li", "ol", "p", "pre", "strong", "ul",
+        ]))
+        .tag_attributes([("a", HashSet::from(["href"]))].into())
+        .url_schemes(HashSet::from(["http", "https", "mailto"]))
+        .link_rel(Some("noopener noreferrer nofollow"))
+        .clean(html)
+        .to_string()
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{Connection, SqliteConnection};
+
+    use super::*;
+
+    fn users() -> HashSet<String> {
+        HashSet::from([String::from("alice")])
+    }
+
+    #[test]
+    fn plain_paragraphs_and_line_breaks() {
+        assert_eq!(render("one\r\ntwo\n\n\n  three  \n\n", Format::Plain, &users()), "<p>one<br>two</p><p>three</p>");
+        assert_eq!(render(" \n\n \n", Format::Plain, &users()), "");
+    }
+
+    #[test]
+    fn plain_is_escaped() {
+        assert_eq!(
+            render("<script>alert(1)</script> <b onclick=\"x\">&'", Format::Plain, &users()),
+            "<p>&lt;script&gt;alert(1)&lt;/script&gt; &lt;b onclick=\"x\"&gt;&amp;'</p>"
+        );
+    }
+
+    #[test]
+    fn markdown_html_is_stripped() {
+        let html = render("<script>alert(1)</script>\n\n<img src=x onerror=alert(1)> <b onmouseover=\"alert(1)\">hi</b>", Format::Markdown, &users());
+        assert!(!html.contains("<script"), "{}", html);
+        assert!(!html.contains("<img"), "{}", html);
+        assert!(!html.contains("onerror"), "{}", html);
+        assert!(!html.contains("onmouseover"), "{}", html);
+    }
+
+    #[test]
+    fn markdown_script_links_are_dropped() {
+        for link in ["[x](javascript:alert(1))", "[x](JaVaScRiPt:alert(1))", "<a href=\"javascript:alert(1)\">x</a>", "[x](data:text/html,<script>alert(1)</script>)"] {
+            let html = render(link, Format::Markdown, &users());
+            assert!(!html.contains("href"), "{} => {}", link, html);
+        }
+        assert_eq!(
+            render("[x](https://example.com)", Format::Markdown, &users()).trim_end(),
+            "<p><a href=\"https://example.com\" rel=\"noopener noreferrer nofollow\">x</a></p>"
+        );
+    }
+
+    #[test]
+    fn urls_stop_at_quotes() {
+        for format in [Format::Plain, Format::Markdown] {
+            let html = render("https://example.com/\"onmouseover=\"alert(1)", format, &users());
+            assert_eq!(html.trim_end(), "<p><a href=\"https://example.com/\" rel=\"noopener noreferrer nofollow\">https://example.com/</a>\"onmouseover=\"alert(1)</p>");
+        }
+        assert_eq!(
+            render("https://example.com/it's", Format::Plain, &users()),
+            "<p><a href=\"https://example.com/it's\" rel=\"noopener noreferrer nofollow\">https://example.com/it's</a></p>"
+        );
+    }
+
+    #[test]
+    fn urls_leave_trailing_punctuation() {
+        assert_eq!(
+            render("see https://example.com/a. (https://example.com/b), https://example.com/c?!", Format::Plain, &users()),
+            "<p>see <a href=\"https://example.com/a\" rel=\"noopener noreferrer nofollow\">https://example.com/a</a>. \
+            (<a href=\"https://example.com/b\" rel=\"noopener noreferrer nofollow\">https://example.com/b</a>), \
+            <a href=\"https://example.com/c\" rel=\"noopener noreferrer nofollow\">https://example.com/c</a>?!</p>"
+        );
+        assert_eq!(tokenise("https://"), vec![Token::Text("https://")]);
+    }
+
+    #[test]
+    fn mentions_and_hashtags() {
+        assert_eq!(
+            render("hi @alice and @bob, me@alice.com #Rust-lang", Format::Plain, &users()),
+            "<p>hi <a href=\"/user/alice\" rel=\"noopener noreferrer nofollow\">@alice</a> and @bob, me@alice.com \
+            <a href=\"/tag/rust-lang\" rel=\"noopener noreferrer nofollow\">#Rust-lang</a></p>"
+        );
+    }
+
+    #[test]
+    fn mentions_in_code_and_links_are_left_alone() {
+        let body = "`@alice` [@alice #tag](https://example.com)\n\n```\n@alice #tag\n```";
+        let html = render(body, Format::Markdown, &users());
+        assert!(!html.contains("/user/alice"), "{}", html);
+        assert!(!html.contains("/tag/"), "{}", html);
+        assert!(html.contains("<code>@alice</code>"), "{}", html);
+        let entities = entities(&format!("{}\n\n@bob #Rust #rust", body), Format::Markdown);
+        assert_eq!(entities.mentions, vec!["bob"]);
+        assert_eq!(entities.hashtags, vec!["rust"]);
+    }
+
+    /// The migration adding `body_html` renders existing posts in SQL, which has to agree with `plain`
+    /// for bodies without anything to link
+    #[tokio::test]
+    async fn backfill_matches_plain() {
+        let bodies = [
+            "one line",
+            "   ",
+            "one\n\ntwo",
+            "one\r\n\r\ntwo\r\nthree",
+            "one\n\n\n\ntwo",
+            "\n\nsurrounded\n\n",
+            "<script>&'\"</script>",
+            "\u{a0}no-break\u{a0}",
+            "\u{3000}ideographic\u{2028}\n\n\u{2003}\u{85}",
+            "\ttabbed\n\tlines\u{b}\u{c}",
+            "unicode 🌱\n\nüñï",
+        ];
+        let mut db = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        let migrator = sqlx::migrate!();
+        for migration in migrator.iter().filter(|m| m.version < 10) {
+            sqlx::raw_sql(&migration.sql).execute(&mut db).await.unwrap();
+        }
+        for body in bodies {
+            sqlx::query("INSERT INTO posts (user_id, body) VALUES (1, $1)").bind(body).execute(&mut db).await.unwrap();
+        }
+        let backfill = migrator.iter().find(|m| m.version == 10).unwrap();
+        sqlx::raw_sql(&backfill.sql).execute(&mut db).await.unwrap();
+        let posts: Vec<(String, String)> = sqlx::query_as("SELECT body, body_html FROM posts ORDER BY id").fetch_all(&mut db).await.unwrap();
+        assert_eq!(posts.len(), bodies.len());
+        for (body, html) in posts {
+            assert_eq!(html, plain(&body, &HashSet::new()), "{:?}", body);
+        }
+    }
+}
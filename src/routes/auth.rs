@@ -1,11 +1,17 @@
 use askama_axum::IntoResponse;
-use axum::{extract::Query, http::StatusCode, response::Redirect, routing::{get, post}, Form, Router};
+use axum::{extract::Query, http::StatusCode, response::Redirect, routing::{get, post}, Form, Json, Router};
+use axum_login::AuthnBackend;
 use axum_messages::Messages;
 use fomat_macros::fomat;
+use serde_json::json;
+use tower_sessions::Session;
 
-use crate::param::{LoginCredentials, NextUrl, RegisterCredentials};
-use crate::template::{LoginTemplate, RegisterTemplate};
-use crate::authentication::AuthSession;
+use crate::model::AuthUser;
+use crate::param::{ForgotPasswordDetails, LoginCredentials, NextUrl, PasskeyLoginDetails, RegisterCredentials, ResetPasswordDetails, TokenQuery, TwoFactorCode};
+use crate::template::{ForgotPasswordTemplate, LoginTemplate, RegisterTemplate, ResetPasswordTemplate, TwoFactorLoginTemplate};
+use crate::authentication::{AuthSession, Credentials};
+use crate::passkey::{Challenge, AUTHENTICATION_KEY};
+use crate::two_factor::{PendingLogin, Verification, PENDING_LOGIN_KEY};
 
 
 pub fn router() -> Router {
@@ -14,11 +20,20 @@ pub fn router() -> Router {
         .route("/register", get(self::get::register))
         .route("/login", post(self::post::login))
         .route("/login", get(self::get::login))
+        .route("/login/two-factor", post(self::post::two_factor))
+        .route("/login/two-factor", get(self::get::two_factor))
+        .route("/login/passkey/options", post(self::post::passkey_options))
+        .route("/login/passkey", post(self::post::passkey))
         .route("/logout", get(self::get::logout))
+        .route("/verify-email", get(self::get::verify_email))
+        .route("/forgot-password", get(self::get::forgot_password))
+        .route("/forgot-password", post(self::post::forgot_password))
+        .route("/reset-password", get(self::get::reset_password))
+        .route("/reset-password", post(self::post::reset_password))
 }
 
-async fn _login(mut auth_session: AuthSession, messages: Messages, creds: LoginCredentials) -> Result<Redirect, StatusCode> {
-    let user = match auth_session.authenticate(creds.clone()).await {
+async fn _login(auth_session: AuthSession, session: Session, messages: Messages, creds: LoginCredentials) -> Result<Redirect, StatusCode> {
+    let user = match auth_session.authenticate(Credentials::Password(creds.clone())).await {
         Ok(Some(user)) => user,
         Ok(None) => {
             messages.error("Invalid credentials");
@@ -30,28 +45,44 @@ async fn _login(mut auth_session: AuthSession, messages: Messages, creds: LoginC
         },
         Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR)
     };
+    match auth_session.backend.get_two_factor(user.id).await {
+        // The password was right, but the session isn't logged in until a code is entered too
+        Ok(two_factor) if two_factor.totp_enabled == 1 => {
+            if let Err(e) = session.insert(PENDING_LOGIN_KEY, PendingLogin::new(user.id, creds.next)).await {
+                println!("{:?}", e);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            return Ok(Redirect::to("/login/two-factor"));
+        },
+        Ok(_) => (),
+        Err(e) => {
+            println!("{:?}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        },
+    }
+    _finish_login(auth_session, messages, user, creds.next).await.map(|next| Redirect::to(&next))
+}
+
+/// Logs the session in once every step is done, returning where to go next
+async fn _finish_login(mut auth_session: AuthSession, messages: Messages, user: AuthUser, next: Option<String>) -> Result<String, StatusCode> {
     if auth_session.login(&user).await.is_err() {
         return Err(StatusCode::INTERNAL_SERVER_ERROR);
     }
     messages.success(fomat!("Successfully logged in as "(user.username)));
-    if let Some(ref next) = creds.next {
-        return Ok(Redirect::to(next));
-    } else {
-        return Ok(Redirect::to("/dash"));
-    }
+    Ok(next.unwrap_or_else(|| String::from("/dash")))
 }
 
 mod post {
     use super::*;
 
-    pub async fn login(auth_session: AuthSession, messages: Messages, Form(credentials): Form<LoginCredentials>) -> impl IntoResponse {
-        match _login(auth_session, messages, credentials).await {
+    pub async fn login(auth_session: AuthSession, session: Session, messages: Messages, Form(credentials): Form<LoginCredentials>) -> impl IntoResponse {
+        match _login(auth_session, session, messages, credentials).await {
             Ok(r) => r.into_response(),
             Err(c) => c.into_response(),
         }
     }
 
-    pub async fn register(auth_session: AuthSession, messages: Messages, Form(credentials): Form<RegisterCredentials>) -> impl IntoResponse {
+    pub async fn register(auth_session: AuthSession, session: Session, messages: Messages, Form(credentials): Form<RegisterCredentials>) -> impl IntoResponse {
         let creds = match auth_session.backend.register(&credentials).await {
             Ok(Some(creds)) => creds,
             Ok(None) => {
@@ -68,11 +99,122 @@ mod post {
             },
         };
         messages.clone().success(fomat!("Registered user "(&credentials.username)));
-        match _login(auth_session, messages, creds).await {
+        match _login(auth_session, session, messages, creds).await {
             Ok(r) => r.into_response(),
             Err(c) => c.into_response(),
         }
     }
+
+    pub async fn two_factor(auth_session: AuthSession, session: Session, messages: Messages, Form(TwoFactorCode{code}): Form<TwoFactorCode>) -> impl IntoResponse {
+        let pending: PendingLogin = match session.get(PENDING_LOGIN_KEY).await {
+            Ok(Some(p)) => p,
+            Ok(None) => return Redirect::to("/login").into_response(),
+            Err(e) => {
+                println!("{:?}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            },
+        };
+        if pending.is_expired() {
+            let _ = session.remove::<PendingLogin>(PENDING_LOGIN_KEY).await;
+            messages.error("That took too long, log in again");
+            return Redirect::to("/login").into_response();
+        }
+        match auth_session.backend.verify_second_factor(pending.user_id, &code).await {
+            Ok(Verification::Accepted) => (),
+            Ok(Verification::Rejected) => {
+                messages.error("That code was wrong");
+                return Redirect::to("/login/two-factor").into_response();
+            },
+            // Guessing codes means waiting out the lockout and entering the password again
+            Ok(Verification::LockedOut) => {
+                let _ = session.remove::<PendingLogin>(PENDING_LOGIN_KEY).await;
+                messages.error("Too many wrong codes, wait a few minutes and log in again");
+                return Redirect::to("/login").into_response();
+            },
+            Err(e) => {
+                println!("{:?}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            },
+        }
+        let _ = session.remove::<PendingLogin>(PENDING_LOGIN_KEY).await;
+        let user = match AuthnBackend::get_user(&auth_session.backend, &pending.user_id).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return Redirect::to("/login").into_response(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        match _finish_login(auth_session, messages, user, pending.next).await {
+            Ok(next) => Redirect::to(&next).into_response(),
+            Err(c) => c.into_response(),
+        }
+    }
+
+    pub async fn passkey_options(auth_session: AuthSession, session: Session) -> impl IntoResponse {
+        let challenge = Challenge::new();
+        if let Err(e) = session.insert(AUTHENTICATION_KEY, &challenge).await {
+            println!("{:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        Json(auth_session.backend.relying_party.request_options(&challenge)).into_response()
+    }
+
+    /// Passkeys are asked for by a script, which is told where to go rather than redirected
+    pub async fn passkey(auth_session: AuthSession, session: Session, messages: Messages, Json(details): Json<PasskeyLoginDetails>) -> impl IntoResponse {
+        // Each challenge can only be answered once
+        let challenge = match session.remove::<Challenge>(AUTHENTICATION_KEY).await {
+            Ok(Some(c)) => c,
+            Ok(None) => return StatusCode::BAD_REQUEST.into_response(),
+            Err(e) => {
+                println!("{:?}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            },
+        };
+        let user = match auth_session.authenticate(Credentials::Passkey { challenge, assertion: details.assertion }).await {
+            Ok(Some(user)) => user,
+            Ok(None) => {
+                messages.error("That passkey isn't registered here");
+                return StatusCode::UNAUTHORIZED.into_response();
+            },
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        match _finish_login(auth_session, messages, user, details.next).await {
+            Ok(next) => Json(json!({ "redirect": next })).into_response(),
+            Err(c) => c.into_response(),
+        }
+    }
+
+    pub async fn forgot_password(auth_session: AuthSession, messages: Messages, Form(details): Form<ForgotPasswordDetails>) -> impl IntoResponse {
+        match auth_session.backend.send_password_reset(&details.email).await {
+            Ok(_) => {
+                messages.info("If an account uses that address, we've emailed it a link to reset the password");
+                Redirect::to("/forgot-password").into_response()
+            },
+            Err(e) => {
+                println!("{:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            },
+        }
+    }
+
+    pub async fn reset_password(auth_session: AuthSession, messages: Messages, Form(details): Form<ResetPasswordDetails>) -> impl IntoResponse {
+        if details.password.is_empty() {
+            messages.error("Your new password can't be empty");
+            return Redirect::to(&fomat!("/reset-password?token="(urlencoding::encode(&details.token)))).into_response();
+        }
+        match auth_session.backend.reset_password(&details.token, details.password).await {
+            Ok(true) => {
+                messages.success("Your password has been reset, you can log in with it now. Any passkeys on the account were removed");
+                Redirect::to("/login").into_response()
+            },
+            Ok(false) => {
+                messages.error("That link is invalid or has expired");
+                Redirect::to("/forgot-password").into_response()
+            },
+            Err(e) => {
+                println!("{:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            },
+        }
+    }
 }
 
 mod get {
@@ -92,6 +234,59 @@ mod get {
         }
     }
 
+    pub async fn two_factor(session: Session, messages: Messages) -> impl IntoResponse {
+        match session.get::<PendingLogin>(PENDING_LOGIN_KEY).await {
+            Ok(Some(_)) => TwoFactorLoginTemplate {
+                messages: messages.into_iter().collect(),
+            }.into_response(),
+            _ => Redirect::to("/login").into_response(),
+        }
+    }
+
+    pub async fn forgot_password(messages: Messages) -> ForgotPasswordTemplate {
+        ForgotPasswordTemplate {
+            messages: messages.into_iter().collect(),
+        }
+    }
+
+    pub async fn reset_password(auth_session: AuthSession, messages: Messages, Query(TokenQuery{token}): Query<TokenQuery>) -> impl IntoResponse {
+        match auth_session.backend.check_reset_token(&token).await {
+            Ok(true) => ResetPasswordTemplate {
+                messages: messages.into_iter().collect(),
+                token,
+            }.into_response(),
+            Ok(false) => {
+                messages.error("That link is invalid or has expired");
+                Redirect::to("/forgot-password").into_response()
+            },
+            Err(e) => {
+                println!("{:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            },
+        }
+    }
+
+    pub async fn verify_email(auth_session: AuthSession, messages: Messages, Query(TokenQuery{token}): Query<TokenQuery>) -> impl IntoResponse {
+        let next = match auth_session.user {
+            Some(_) => "/dash",
+            None => "/login",
+        };
+        match auth_session.backend.verify_email(&token).await {
+            Ok(true) => {
+                messages.success("Your email address is verified");
+                Redirect::to(next).into_response()
+            },
+            Ok(false) => {
+                messages.error("That link is invalid or has expired");
+                Redirect::to(next).into_response()
+            },
+            Err(e) => {
+                println!("{:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            },
+        }
+    }
+
     pub async fn logout(mut auth_session: AuthSession) -> impl IntoResponse {
         match auth_session.logout().await {
             Ok(_) => Redirect::to("/").into_response(),
@@ -1,46 +1,190 @@
+use std::convert::Infallible;
+
+use askama::Template;
 use askama_axum::IntoResponse;
-use axum::{http::StatusCode, response::Redirect, routing::{get, post}, Form, Router};
+use axum::{body::Bytes, extract::{multipart::MultipartError, Multipart, Path, Query}, http::{header, HeaderMap, StatusCode}, response::{sse::{Event, KeepAlive}, Redirect, Response, Sse}, routing::{get, post}, Form, Json, Router};
 use axum_messages::Messages;
+use futures::stream;
+use tokio::sync::broadcast::error::RecvError;
+use tower_sessions::Session;
 
-use crate::param::{FollowDetails, PostDetails};
-use crate::template::{DashTemplate, PostTemplate};
-use crate::authentication::AuthSession;
+use crate::media::{Media, MAX_ATTACHMENTS};
+use crate::model::{ContentFilter, NotificationKind, Thread};
+use crate::param::{normalise_tag, AddPasskeyDetails, Cursor, DisableTwoFactorDetails, FollowDetails, FollowRequestDetails, PasskeyDetails, PasskeyOptionsDetails, PasswordDetails, Page, PostDetails, ReplyDetails, ReplyPermission, SessionDetails, SettingsDetails, TagFollowDetails, TwoFactorCode, Upload, Visibility};
+use crate::render::Format;
+use crate::template::{DashTemplate, FollowRequestsTemplate, NotificationsTemplate, PasskeysTemplate, PostTemplate, RecoveryCodesTemplate, SessionsTemplate, SettingsTemplate, ThreadTemplate, TwoFactorTemplate};
+use crate::authentication::{AuthSession, Backend};
+use crate::hub::LiveEvent;
+use crate::passkey::{Challenge, REGISTRATION_KEY};
+use crate::two_factor::Enrolment;
 
 
 pub fn router() -> Router {
     Router::new()
         .route("/dash", get(self::get::home))
+        .route("/dash/live", get(self::get::live))
         .route("/post", get(self::get::post))
         .route("/post", post(self::post::post))
+        .route("/post/:post_id/edit", get(self::get::edit))
+        .route("/post/:post_id/edit", post(self::post::edit))
+        .route("/post/:post_id/delete", post(self::post::delete))
+        .route("/post/:post_id/like", post(self::post::like))
+        .route("/post/:post_id/unlike", post(self::post::unlike))
+        .route("/post/:post_id/reply", post(self::post::reply))
+        .route("/reblog/:post_id", get(self::get::reblog))
+        .route("/reblog/:post_id", post(self::post::reblog))
         .route("/follow", post(self::post::follow))
+        .route("/unfollow", post(self::post::unfollow))
+        .route("/follow-requests", get(self::get::follow_requests))
+        .route("/follow-requests/accept", post(self::post::accept_follow_request))
+        .route("/follow-requests/reject", post(self::post::reject_follow_request))
+        .route("/settings", get(self::get::settings))
+        .route("/settings", post(self::post::settings))
+        .route("/settings/password", post(self::post::change_password))
+        .route("/settings/sessions", get(self::get::sessions))
+        .route("/settings/sessions/revoke", post(self::post::revoke_session))
+        .route("/settings/sessions/revoke-all", post(self::post::revoke_all_sessions))
+        .route("/settings/two-factor", get(self::get::two_factor))
+        .route("/settings/two-factor/setup", post(self::post::setup_two_factor))
+        .route("/settings/two-factor/enable", post(self::post::enable_two_factor))
+        .route("/settings/two-factor/disable", post(self::post::disable_two_factor))
+        .route("/settings/passkeys", get(self::get::passkeys))
+        .route("/settings/passkeys", post(self::post::add_passkey))
+        .route("/settings/passkeys/options", post(self::post::passkey_options))
+        .route("/settings/passkeys/delete", post(self::post::delete_passkey))
+        .route("/notifications", get(self::get::notifications))
+        .route("/verify-email/resend", post(self::post::resend_verification))
+        .route("/notifications/read", post(self::post::read_notifications))
+        .route("/follow/tag", post(self::post::follow_tag))
+        .route("/unfollow/tag", post(self::post::unfollow_tag))
+}
+/// Sends the user back to the page they came from, as long as it was on this site
+fn back(headers: &HeaderMap, fallback: &str) -> Redirect {
+    let host = headers.get(header::HOST).and_then(|h| h.to_str().ok());
+    let path = headers.get(header::REFERER)
+        .and_then(|r| r.to_str().ok())
+        .and_then(|r| r.strip_prefix("https://").or_else(|| r.strip_prefix("http://")))
+        .and_then(|r| r.split_once('/'))
+        .filter(|(authority, _)| Some(*authority) == host)
+        .map(|(_, path)| format!("/{}", path));
+    Redirect::to(path.as_deref().unwrap_or(fallback))
+}
+/// Posting, reblogging and replying wait until the account's email address is verified
+fn unverified(messages: Messages) -> Response {
+    messages.error("Verify your email address before posting");
+    Redirect::to("/dash").into_response()
+}
+
+/// Turns a hub event into something to send down a user's live stream, if it concerns them
+async fn live_event(backend: &Backend, user_id: i64, event: LiveEvent) -> anyhow::Result<Option<Event>> {
+    match event {
+        LiveEvent::Post { post_id, author_id } => {
+            if !backend.is_following(user_id, author_id).await? {
+                return Ok(None);
+            }
+            let post = match backend.get_post(post_id, Some(user_id)).await? {
+                Some(p) => p,
+                None => return Ok(None),
+            };
+            let mut post = post.into(Some(user_id), &backend.db).await?;
+            backend.get_content_filter(Some(user_id)).await?.apply(std::slice::from_mut(&mut post));
+            Ok(Some(Event::default().event("post").data(ThreadTemplate { post }.render()?)))
+        },
+        LiveEvent::Notification { user_id: recipient } if recipient == user_id => {
+            let unread = backend.count_unread_notifications(user_id).await?;
+            Ok(Some(Event::default().event("notifications").data(unread.to_string())))
+        },
+        _ => Ok(None),
+    }
 }
 
 mod get {
     use super::*;
 
-    pub async fn home(auth_session: AuthSession, messages: Messages) -> impl IntoResponse {
+    pub async fn home(auth_session: AuthSession, messages: Messages, Query(Cursor{before}): Query<Cursor>) -> impl IntoResponse {
         match auth_session.user {
-            Some(user) => DashTemplate {
-                messages: messages.into_iter().collect(),
-                user: match user.get_display(&auth_session.backend.db).await {
-                    Ok(u) => u,
-                    Err(e) => {
-                        println!("{:?}", e);
-                        return StatusCode::INTERNAL_SERVER_ERROR.into_response()
-                    }
-                },
-                posts: match auth_session.backend.get_dash_contents(user.id).await {
-                    Ok(c) => c,
-                    Err(e) => {
-                        println!("{:?}", e);
-                        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-                    }
+            Some(user) => match auth_session.backend.get_dash_contents(user.id, before).await {
+                Ok((mut posts, has_next)) => DashTemplate {
+                    messages: messages.into_iter().collect(),
+                    user: match user.get_display(&auth_session.backend.db).await {
+                        Ok(u) => u,
+                        Err(e) => {
+                            println!("{:?}", e);
+                            return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                        }
+                    },
+                    next: match has_next {
+                        true => posts.last().map(|t| t.id),
+                        false => None,
+                    },
+                    live: before.is_none(),
+                    verified: user.email_verified == 1,
+                    unread: match auth_session.backend.count_unread_notifications(user.id).await {
+                        Ok(u) => u,
+                        Err(e) => {
+                            println!("{:?}", e);
+                            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                        }
+                    },
+                    posts: match auth_session.backend.get_content_filter(Some(user.id)).await {
+                        Ok(filter) => {
+                            filter.apply(&mut posts);
+                            if let Err(e) = Thread::mark_liked(&mut posts, Some(user.id), &auth_session.backend.db).await {
+                                println!("{:?}", e);
+                                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                            }
+                            posts
+                        },
+                        Err(e) => {
+                            println!("{:?}", e);
+                            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                        }
+                    },
+                }.into_response(),
+                Err(e) => {
+                    println!("{:?}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
                 }
-            }.into_response(),
+            },
             None => StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 
+    /// Streams new posts from followed accounts and unread notification counts as they happen,
+    /// until the session it was opened from is logged out
+    pub async fn live(auth_session: AuthSession, session: Session) -> impl IntoResponse {
+        let (user_id, session_id) = match (auth_session.user, session.id()) {
+            (Some(user), Some(id)) => (user.id, id.to_string()),
+            _ => return StatusCode::UNAUTHORIZED.into_response()
+        };
+        let backend = auth_session.backend;
+        let receiver = backend.hub.subscribe();
+        let events = stream::unfold((receiver, backend, session_id), move |(mut receiver, backend, session_id)| async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(LiveEvent::Shutdown) | Err(RecvError::Closed) => return None,
+                    Ok(LiveEvent::SessionsRevoked { user_id: revoked }) if revoked != user_id => continue,
+                    // Missed events are gone and may have included a revocation, but the stream can otherwise carry on
+                    Ok(LiveEvent::SessionsRevoked { .. }) | Err(RecvError::Lagged(_)) => match backend.session_exists(&session_id).await {
+                        Ok(true) => continue,
+                        Ok(false) => return None,
+                        Err(e) => {
+                            println!("{:?}", e);
+                            return None;
+                        },
+                    },
+                    Ok(event) => event,
+                };
+                match live_event(&backend, user_id, event).await {
+                    Ok(Some(event)) => return Some((Ok::<_, Infallible>(event), (receiver, backend, session_id))),
+                    Ok(None) => (),
+                    Err(e) => println!("{:?}", e),
+                }
+            }
+        });
+        Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+    }
+
     pub async fn post(auth_session: AuthSession, messages: Messages) -> impl IntoResponse {
         match auth_session.user {
             Some(user) => PostTemplate {
@@ -49,40 +193,774 @@ mod get {
                     Ok(u) => u,
                     Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response()
                 },
+                reblog: None,
+                editing: false,
+                summary: String::new(),
+                body: String::new(),
+                format: String::new(),
+                tags: String::new(),
+                replies: String::new(),
             }.into_response(),
             None => StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
+
+    pub async fn edit(auth_session: AuthSession, messages: Messages, Path(post_id): Path<i64>) -> impl IntoResponse {
+        let user = match auth_session.user {
+            Some(user) => user,
+            None => return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        };
+        let post = match auth_session.backend.get_post(post_id, Some(user.id)).await {
+            Ok(Some(p)) if p.username == user.username => p,
+            Ok(_) => return StatusCode::NOT_FOUND.into_response(),
+            Err(e) => {
+                println!("{:?}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        let summary = post.summary.clone().unwrap_or_default();
+        let mut thread = match post.into(Some(user.id), &auth_session.backend.db).await {
+            Ok(t) => t,
+            Err(e) => {
+                println!("{:?}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        let (body, format, replies) = match thread.contents.pop() {
+            Some(p) => (p.body, p.format, p.reply_permission),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        };
+        PostTemplate {
+            messages: messages.into_iter().collect(),
+            user: match user.get_display(&auth_session.backend.db).await {
+                Ok(u) => u,
+                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            },
+            reblog: None,
+            editing: true,
+            summary,
+            body,
+            format,
+            tags: thread.tags.join(", "),
+            replies,
+        }.into_response()
+    }
+
+    pub async fn follow_requests(auth_session: AuthSession, messages: Messages) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => match auth_session.backend.get_follow_requests(user.id).await {
+                Ok(requests) => FollowRequestsTemplate {
+                    messages: messages.into_iter().collect(),
+                    requests,
+                }.into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            },
+            None => StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+
+    pub async fn settings(auth_session: AuthSession, messages: Messages) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => {
+                let approve_followers = match auth_session.backend.approves_followers(user.id).await {
+                    Ok(a) => a,
+                    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                };
+                let muted = match auth_session.backend.get_notification_mutes(user.id).await {
+                    Ok(m) => m,
+                    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                };
+                match auth_session.backend.get_content_filter(Some(user.id)).await {
+                    Ok(filter) => SettingsTemplate {
+                        messages: messages.into_iter().collect(),
+                        approve_followers,
+                        cw_expand: filter.expand,
+                        cw_hidden_words: filter.hidden_words.join(", "),
+                        notification_kinds: NotificationKind::ALL,
+                        muted,
+                    }.into_response(),
+                    Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+            None => StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+
+    pub async fn sessions(auth_session: AuthSession, session: Session, messages: Messages) -> impl IntoResponse {
+        let user = match auth_session.user {
+            Some(user) => user,
+            None => return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        };
+        let sessions = match auth_session.backend.get_sessions(user.id).await {
+            Ok(sessions) => sessions,
+            Err(e) => {
+                println!("{:?}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        match auth_session.backend.get_passkeys(user.id).await {
+            Ok(passkeys) => SessionsTemplate {
+                messages: messages.into_iter().collect(),
+                sessions,
+                current: session.id().map(|id| id.to_string()).unwrap_or_default(),
+                passkeys,
+            }.into_response(),
+            Err(e) => {
+                println!("{:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    pub async fn two_factor(auth_session: AuthSession, messages: Messages) -> impl IntoResponse {
+        let user = match auth_session.user {
+            Some(user) => user,
+            None => return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        };
+        let two_factor = match auth_session.backend.get_two_factor(user.id).await {
+            Ok(t) => t,
+            Err(e) => {
+                println!("{:?}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        let enabled = two_factor.totp_enabled == 1;
+        let enrolment = match two_factor.totp_secret {
+            Some(secret) if !enabled => match Enrolment::new(&secret, &user.username) {
+                Some(e) => Some(e),
+                None => return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            },
+            _ => None,
+        };
+        TwoFactorTemplate {
+            messages: messages.into_iter().collect(),
+            enabled,
+            recovery_codes: two_factor.recovery_codes,
+            enrolment,
+        }.into_response()
+    }
+
+    pub async fn passkeys(auth_session: AuthSession, messages: Messages) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => match auth_session.backend.get_passkeys(user.id).await {
+                Ok(passkeys) => PasskeysTemplate {
+                    messages: messages.into_iter().collect(),
+                    passkeys,
+                }.into_response(),
+                Err(e) => {
+                    println!("{:?}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+            None => StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+
+    pub async fn notifications(auth_session: AuthSession, Query(Page{page}): Query<Page>) -> impl IntoResponse {
+        let page = page.unwrap_or(0).max(0);
+        match auth_session.user {
+            Some(user) => match auth_session.backend.get_notifications(user.id, page).await {
+                Ok((notifications, has_next)) => NotificationsTemplate {
+                    notifications,
+                    page,
+                    has_next,
+                }.into_response(),
+                Err(e) => {
+                    println!("{:?}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+            None => StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+
+    pub async fn reblog(auth_session: AuthSession, messages: Messages, Path(post_id): Path<i64>) -> impl IntoResponse {
+        let user = match auth_session.user {
+            Some(user) => user,
+            None => return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        };
+        let parent = match auth_session.backend.get_post(post_id, Some(user.id)).await {
+            Ok(Some(p)) => p,
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Err(e) => {
+                println!("{:?}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        match auth_session.backend.can_reblog(post_id).await {
+            Ok(true) => (),
+            Ok(false) => return StatusCode::FORBIDDEN.into_response(),
+            Err(e) => {
+                println!("{:?}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+        PostTemplate {
+            messages: messages.into_iter().collect(),
+            user: match user.get_display(&auth_session.backend.db).await {
+                Ok(u) => u,
+                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            },
+            reblog: match parent.into(Some(user.id), &auth_session.backend.db).await {
+                Ok(mut t) => {
+                    if let Ok(filter) = auth_session.backend.get_content_filter(Some(user.id)).await {
+                        filter.apply(std::slice::from_mut(&mut t));
+                    }
+                    Some(t)
+                },
+                Err(e) => {
+                    println!("{:?}", e);
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            },
+            editing: false,
+            summary: String::new(),
+            body: String::new(),
+            format: String::new(),
+            tags: String::new(),
+            replies: String::new(),
+        }.into_response()
+    }
+}
+
+/// Reads the compose form, pairing each non-empty file with the alt text field that follows it
+async fn read_post(mut multipart: Multipart) -> Result<(PostDetails, Vec<(Bytes, String)>), MultipartError> {
+    let mut post = PostDetails::default();
+    let mut files = Vec::new();
+    let mut alts = Vec::new();
+    while let Some(field) = multipart.next_field().await? {
+        let name = field.name().unwrap_or_default().to_string();
+        match name.as_str() {
+            "media" => files.push(field.bytes().await?),
+            "alt" => alts.push(field.text().await?),
+            "summary" => post.summary = field.text().await?,
+            "body" => post.body = field.text().await?,
+            "tags" => post.tags = field.text().await?,
+            "format" => post.format = match field.text().await?.as_str() {
+                "markdown" => Format::Markdown,
+                _ => Format::Plain,
+            },
+            "replies" => post.replies = match field.text().await?.as_str() {
+                "followers" => ReplyPermission::Followers,
+                "nobody" => ReplyPermission::Nobody,
+                _ => ReplyPermission::Everyone,
+            },
+            "visibility" => post.visibility = match field.text().await?.as_str() {
+                "unlisted" => Visibility::Unlisted,
+                "followers" => Visibility::Followers,
+                "mentioned" => Visibility::Mentioned,
+                _ => Visibility::Public,
+            },
+            _ => (),
+        }
+    }
+    Ok((
+        post,
+        files.into_iter().zip(alts).filter(|(data, _)| !data.is_empty()).collect(),
+    ))
 }
 
 mod post {
     use super::*;
 
-    pub async fn post(auth_session: AuthSession, Form(post): Form<PostDetails>) -> impl IntoResponse {
+    pub async fn post(auth_session: AuthSession, messages: Messages, multipart: Multipart) -> impl IntoResponse {
         match auth_session.user {
             Some(user) => {
-                sqlx::query("INSERT INTO posts (user_id, body) VALUES ($1, $2)")
-                    .bind(user.id)
-                    .bind(post.body)
-                    .execute(&auth_session.backend.db)
-                    .await
-                    .expect("Unable to create new post");
-                Redirect::to("/dash").into_response()
+                if user.email_verified == 0 {
+                    return unverified(messages);
+                }
+                let (post, files) = match read_post(multipart).await {
+                    Ok(p) => p,
+                    Err(e) => return e.into_response(),
+                };
+                let mut uploads = Vec::new();
+                for (data, alt) in files {
+                    let error = if data.len() > auth_session.backend.media.max_size {
+                        Some(format!("Attachments must be smaller than {}KB", auth_session.backend.media.max_size / 1024))
+                    } else if alt.trim().is_empty() {
+                        Some("Every attachment needs alt text".to_string())
+                    } else {
+                        None
+                    };
+                    let mime = match (error, Media::sniff(&data)) {
+                        (None, Some(mime)) => mime,
+                        (Some(error), _) => {
+                            messages.error(error);
+                            return Redirect::to("/post").into_response();
+                        },
+                        (None, None) => {
+                            messages.error("Unsupported attachment type");
+                            return Redirect::to("/post").into_response();
+                        }
+                    };
+                    uploads.push(Upload { data: data.to_vec(), mime, alt });
+                }
+                if uploads.len() > MAX_ATTACHMENTS {
+                    messages.error(format!("Posts can have at most {} attachments", MAX_ATTACHMENTS));
+                    return Redirect::to("/post").into_response();
+                }
+                match auth_session.backend.create_post(user.id, &post, &uploads).await {
+                    Ok(_) => Redirect::to("/dash").into_response(),
+                    Err(e) => {
+                        println!("{:?}", e);
+                        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                    }
+                }
             },
             None => StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 
-    pub async fn follow(auth_session: AuthSession, Form(follow): Form<FollowDetails>) -> impl IntoResponse {
+    pub async fn edit(auth_session: AuthSession, Path(post_id): Path<i64>, Form(post): Form<PostDetails>) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => match auth_session.backend.edit_post(user.id, post_id, &post).await {
+                Ok(true) => Redirect::to(&format!("/user/{}/post/{}", user.username, post_id)).into_response(),
+                Ok(false) => StatusCode::NOT_FOUND.into_response(),
+                Err(e) => {
+                    println!("{:?}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+            None => StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+
+    pub async fn delete(auth_session: AuthSession, messages: Messages, Path(post_id): Path<i64>) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => match auth_session.backend.delete_post(user.id, post_id).await {
+                Ok(true) => {
+                    messages.success("Post deleted");
+                    Redirect::to("/dash").into_response()
+                },
+                Ok(false) => StatusCode::NOT_FOUND.into_response(),
+                Err(e) => {
+                    println!("{:?}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+            None => StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+
+    pub async fn like(auth_session: AuthSession, headers: HeaderMap, Path(post_id): Path<i64>) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => match auth_session.backend.like(user.id, post_id).await {
+                Ok(Some(author)) => back(&headers, &format!("/user/{}/post/{}", author, post_id)).into_response(),
+                Ok(None) => StatusCode::NOT_FOUND.into_response(),
+                Err(e) => {
+                    println!("{:?}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+            None => StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+
+    pub async fn unlike(auth_session: AuthSession, headers: HeaderMap, Path(post_id): Path<i64>) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => match auth_session.backend.unlike(user.id, post_id).await {
+                Ok(_) => back(&headers, "/dash").into_response(),
+                Err(e) => {
+                    println!("{:?}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+            None => StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+
+    pub async fn reply(auth_session: AuthSession, messages: Messages, Path(post_id): Path<i64>, Form(reply): Form<ReplyDetails>) -> impl IntoResponse {
+        let user = match auth_session.user {
+            Some(user) => user,
+            None => return StatusCode::UNAUTHORIZED.into_response()
+        };
+        if user.email_verified == 0 {
+            return unverified(messages);
+        }
+        let post = match auth_session.backend.get_post(post_id, Some(user.id)).await {
+            Ok(Some(p)) => p,
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Err(e) => {
+                println!("{:?}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        let permalink = format!("/user/{}/post/{}", post.username, post.id);
+        let body = reply.body.trim();
+        if body.is_empty() {
+            messages.error("Replies can't be empty");
+            return Redirect::to(&permalink).into_response();
+        }
+        match auth_session.backend.reply(user.id, post_id, body).await {
+            Ok(true) => Redirect::to(&format!("{}#replies", permalink)).into_response(),
+            Ok(false) => StatusCode::FORBIDDEN.into_response(),
+            Err(e) => {
+                println!("{:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    pub async fn reblog(auth_session: AuthSession, messages: Messages, Path(post_id): Path<i64>, Form(post): Form<PostDetails>) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => {
+                if user.email_verified == 0 {
+                    return unverified(messages);
+                }
+                let parent = match auth_session.backend.get_post(post_id, Some(user.id)).await {
+                    Ok(Some(p)) => p,
+                    Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+                    Err(e) => {
+                        println!("{:?}", e);
+                        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                    }
+                };
+                match auth_session.backend.can_reblog(post_id).await {
+                    Ok(true) => (),
+                    Ok(false) => return StatusCode::FORBIDDEN.into_response(),
+                    Err(e) => {
+                        println!("{:?}", e);
+                        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                    }
+                }
+                match auth_session.backend.reblog(user.id, &parent, &post).await {
+                    Ok(_) => Redirect::to("/dash").into_response(),
+                    Err(e) => {
+                        println!("{:?}", e);
+                        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                    }
+                }
+            },
+            None => StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+
+    pub async fn follow(auth_session: AuthSession, messages: Messages, Form(follow): Form<FollowDetails>) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => {
+                match auth_session.backend.follow(user.id, follow.id).await {
+                    Ok(accepted) => {
+                        if !accepted {
+                            messages.info(format!("Requested to follow {}", follow.name));
+                        }
+                        Redirect::to(&format!("/user/{}", follow.name)).into_response()
+                    },
+                    Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+            None => StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+
+    pub async fn unfollow(auth_session: AuthSession, Form(follow): Form<FollowDetails>) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => match auth_session.backend.unfollow(user.id, follow.id).await {
+                Ok(_) => Redirect::to(&format!("/user/{}", follow.name)).into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            },
+            None => StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+
+    pub async fn accept_follow_request(auth_session: AuthSession, Form(request): Form<FollowRequestDetails>) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => match auth_session.backend.accept_follow_request(user.id, request.id).await {
+                Ok(_) => Redirect::to("/follow-requests").into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            },
+            None => StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+
+    pub async fn reject_follow_request(auth_session: AuthSession, Form(request): Form<FollowRequestDetails>) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => match auth_session.backend.reject_follow_request(user.id, request.id).await {
+                Ok(_) => Redirect::to("/follow-requests").into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            },
+            None => StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+
+    pub async fn settings(auth_session: AuthSession, messages: Messages, Form(settings): Form<SettingsDetails>) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => {
+                if auth_session.backend.set_approve_followers(user.id, settings.approve_followers.is_some()).await.is_err() {
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+                if auth_session.backend.set_notification_mutes(user.id, &settings.muted()).await.is_err() {
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+                let filter = ContentFilter::new(settings.cw_expand.is_some(), &settings.cw_hidden_words);
+                match auth_session.backend.set_content_filter(user.id, &filter).await {
+                    Ok(_) => {
+                        messages.success("Settings saved");
+                        Redirect::to("/settings").into_response()
+                    },
+                    Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+            None => StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+
+    pub async fn change_password(mut auth_session: AuthSession, session: Session, messages: Messages, Form(password): Form<PasswordDetails>) -> impl IntoResponse {
+        let user = match &auth_session.user {
+            Some(user) => user.clone(),
+            None => return StatusCode::UNAUTHORIZED.into_response()
+        };
+        if password.new_password.is_empty() {
+            messages.error("Your new password can't be empty");
+            return Redirect::to("/settings").into_response();
+        }
+        let current = session.id().map(|id| id.to_string());
+        match auth_session.backend.change_password(&user, password.current_password, password.new_password, current).await {
+            Ok(Some(user)) => {
+                // This session still holds the old password's hash and would be logged out without it
+                if auth_session.login(&user).await.is_err() {
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+                messages.success("Password changed, your other sessions have been logged out");
+                Redirect::to("/settings").into_response()
+            },
+            Ok(None) => {
+                messages.error("Your current password was wrong");
+                Redirect::to("/settings").into_response()
+            },
+            Err(e) => {
+                println!("{:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    pub async fn setup_two_factor(auth_session: AuthSession) -> impl IntoResponse {
+        let user = match auth_session.user {
+            Some(user) => user,
+            None => return StatusCode::UNAUTHORIZED.into_response()
+        };
+        match auth_session.backend.start_two_factor(user.id).await {
+            Ok(_) => Redirect::to("/settings/two-factor").into_response(),
+            Err(e) => {
+                println!("{:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    pub async fn enable_two_factor(auth_session: AuthSession, messages: Messages, Form(TwoFactorCode{code}): Form<TwoFactorCode>) -> impl IntoResponse {
+        let user = match auth_session.user {
+            Some(user) => user,
+            None => return StatusCode::UNAUTHORIZED.into_response()
+        };
+        match auth_session.backend.enable_two_factor(user.id, &code).await {
+            Ok(Some(codes)) => RecoveryCodesTemplate { codes }.into_response(),
+            Ok(None) => {
+                messages.error("That code didn't match, check the time on your device is right");
+                Redirect::to("/settings/two-factor").into_response()
+            },
+            Err(e) => {
+                println!("{:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    pub async fn disable_two_factor(auth_session: AuthSession, messages: Messages, Form(details): Form<DisableTwoFactorDetails>) -> impl IntoResponse {
+        let user = match auth_session.user {
+            Some(user) => user,
+            None => return StatusCode::UNAUTHORIZED.into_response()
+        };
+        match auth_session.backend.disable_two_factor(&user, details.password).await {
+            Ok(true) => {
+                messages.success("Two-factor authentication is off");
+                Redirect::to("/settings/two-factor").into_response()
+            },
+            Ok(false) => {
+                messages.error("Your current password was wrong");
+                Redirect::to("/settings/two-factor").into_response()
+            },
+            Err(e) => {
+                println!("{:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    pub async fn passkey_options(auth_session: AuthSession, session: Session, messages: Messages, Json(details): Json<PasskeyOptionsDetails>) -> impl IntoResponse {
+        let user = match auth_session.user {
+            Some(user) => user,
+            None => return StatusCode::UNAUTHORIZED.into_response()
+        };
+        match auth_session.backend.check_password(&user, details.password).await {
+            Ok(true) => (),
+            Ok(false) => {
+                messages.error("Your current password was wrong");
+                return StatusCode::FORBIDDEN.into_response();
+            },
+            Err(e) => {
+                println!("{:?}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+        let existing: Vec<String> = match auth_session.backend.get_passkeys(user.id).await {
+            Ok(passkeys) => passkeys.into_iter().map(|p| p.id).collect(),
+            Err(e) => {
+                println!("{:?}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        let challenge = Challenge::new();
+        if let Err(e) = session.insert(REGISTRATION_KEY, &challenge).await {
+            println!("{:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        Json(auth_session.backend.relying_party.creation_options(&challenge, user.id, &user.username, &existing)).into_response()
+    }
+
+    /// Sent by the script on the passkeys page, which reloads it to show the outcome
+    pub async fn add_passkey(auth_session: AuthSession, session: Session, messages: Messages, Json(details): Json<AddPasskeyDetails>) -> impl IntoResponse {
+        let user = match auth_session.user {
+            Some(user) => user,
+            None => return StatusCode::UNAUTHORIZED.into_response()
+        };
+        let challenge = match session.remove::<Challenge>(REGISTRATION_KEY).await {
+            Ok(Some(c)) => c,
+            Ok(None) => return StatusCode::BAD_REQUEST.into_response(),
+            Err(e) => {
+                println!("{:?}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        match auth_session.backend.check_password(&user, details.password).await {
+            Ok(true) => (),
+            Ok(false) => {
+                messages.error("Your current password was wrong");
+                return StatusCode::FORBIDDEN.into_response();
+            },
+            Err(e) => {
+                println!("{:?}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+        match auth_session.backend.add_passkey(user.id, &challenge, &details.registration).await {
+            Ok(true) => {
+                messages.success("Passkey added, you can log in with it now");
+                StatusCode::CREATED.into_response()
+            },
+            Ok(false) => {
+                messages.error("That passkey couldn't be added");
+                StatusCode::BAD_REQUEST.into_response()
+            },
+            Err(e) => {
+                println!("{:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    pub async fn delete_passkey(auth_session: AuthSession, messages: Messages, Form(passkey): Form<PasskeyDetails>) -> impl IntoResponse {
+        let user = match auth_session.user {
+            Some(user) => user,
+            None => return StatusCode::UNAUTHORIZED.into_response()
+        };
+        match auth_session.backend.delete_passkey(user.id, &passkey.id).await {
+            Ok(_) => {
+                messages.success("Passkey removed");
+                Redirect::to("/settings/passkeys").into_response()
+            },
+            Err(e) => {
+                println!("{:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    pub async fn revoke_session(mut auth_session: AuthSession, session: Session, messages: Messages, Form(revoke): Form<SessionDetails>) -> impl IntoResponse {
+        let user = match &auth_session.user {
+            Some(user) => user.clone(),
+            None => return StatusCode::UNAUTHORIZED.into_response()
+        };
+        if session.id().is_some_and(|id| id.to_string() == revoke.id) {
+            return match auth_session.logout().await {
+                Ok(_) => Redirect::to("/").into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            };
+        }
+        match auth_session.backend.revoke_session(user.id, &revoke.id).await {
+            Ok(_) => {
+                messages.success("Session logged out");
+                Redirect::to("/settings/sessions").into_response()
+            },
+            Err(e) => {
+                println!("{:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    pub async fn revoke_all_sessions(mut auth_session: AuthSession) -> impl IntoResponse {
+        let user = match &auth_session.user {
+            Some(user) => user.clone(),
+            None => return StatusCode::UNAUTHORIZED.into_response()
+        };
+        if let Err(e) = auth_session.backend.revoke_all_sessions(user.id).await {
+            println!("{:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        match auth_session.logout().await {
+            Ok(_) => Redirect::to("/login").into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn resend_verification(auth_session: AuthSession, messages: Messages) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) if user.email_verified == 0 => match auth_session.backend.send_verification(user.id, &user.email).await {
+                Ok(_) => {
+                    messages.success(format!("Sent a new verification link to {}", user.email));
+                    Redirect::to("/dash").into_response()
+                },
+                Err(e) => {
+                    println!("{:?}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+            Some(_) => Redirect::to("/dash").into_response(),
+            None => StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+
+    pub async fn read_notifications(auth_session: AuthSession) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => match auth_session.backend.mark_notifications_read(user.id).await {
+                Ok(_) => Redirect::to("/notifications").into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            },
+            None => StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+
+    pub async fn follow_tag(auth_session: AuthSession, Form(follow): Form<TagFollowDetails>) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => {
+                let tag = normalise_tag(&follow.tag);
+                match auth_session.backend.follow_tag(user.id, &tag).await {
+                    Ok(_) => Redirect::to(&format!("/tag/{}", urlencoding::encode(&tag))).into_response(),
+                    Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+            None => StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+
+    pub async fn unfollow_tag(auth_session: AuthSession, Form(follow): Form<TagFollowDetails>) -> impl IntoResponse {
         match auth_session.user {
             Some(user) => {
-                match sqlx::query("INSERT INTO follows (follower, followee, is_accepted) VALUES ($1, $2, 1)")
-                    .bind(user.id)
-                    .bind(follow.id)
-                    .execute(&auth_session.backend.db)
-                    .await {
-                        Ok(_) => Redirect::to(&format!("/user/{}", follow.name)).into_response(),
-                        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                let tag = normalise_tag(&follow.tag);
+                match auth_session.backend.unfollow_tag(user.id, &tag).await {
+                    Ok(_) => Redirect::to(&format!("/tag/{}", urlencoding::encode(&tag))).into_response(),
+                    Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response()
                 }
             },
             None => StatusCode::UNAUTHORIZED.into_response()
@@ -1,17 +1,26 @@
 use askama_axum::IntoResponse;
-use axum::{extract::Path, http::StatusCode, routing::get, Router};
+use axum::{extract::{Path, Query}, http::{header, HeaderMap, StatusCode}, routing::get, Router};
 use axum::response::Redirect;
-use ::futures::future::join_all;
+use axum_messages::Messages;
 
 use crate::{model::Thread, template::HomeTemplate};
 
-use crate::template::UserTemplate;
+use crate::param::{normalise_tag, Page};
+use crate::template::{FollowListTemplate, LikesTemplate, NotesTemplate, PermalinkTemplate, RevisionsTemplate, TagTemplate, UserTemplate};
 use crate::authentication::AuthSession;
 
 pub fn router() -> Router {
     Router::new()
         .route("/", get(self::get::home))
         .route("/user/:name", get(self::get::user))
+        .route("/user/:name/post/:id", get(self::get::permalink))
+        .route("/user/:name/post/:id/revisions", get(self::get::revisions))
+        .route("/user/:name/post/:id/notes", get(self::get::notes))
+        .route("/user/:name/followers", get(self::get::followers))
+        .route("/user/:name/following", get(self::get::following))
+        .route("/user/:name/likes", get(self::get::likes))
+        .route("/tag/:tag", get(self::get::tag))
+        .route("/media/:key", get(self::get::media))
 }
 
 mod get {
@@ -28,16 +37,41 @@ mod get {
         match auth_session.backend.get_user(&name).await {
             Ok(u) => match u {
                 Some(u) => {
-                    let posts = auth_session.backend.get_posts(u.id).await;
+                    let viewer = auth_session.user.as_ref().map(|v| v.id);
+                    let visible = match auth_session.backend.can_view_posts(viewer, u.id).await {
+                        Ok(v) => v,
+                        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                    };
+                    let posts = match visible {
+                        true => auth_session.backend.get_posts(u.id, viewer).await,
+                        false => Ok(Vec::new()),
+                    };
                     match posts {
                         Ok(posts) => {
-                            let posts: Vec<Thread> = join_all(posts.into_iter().map(|x| x.into(&auth_session.backend.db))).await.into_iter().map(|x|x.unwrap()).collect();
+                            let mut posts = match Thread::from_posts(posts, viewer, &auth_session.backend.db).await {
+                                Ok(p) => p,
+                                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                            };
+                            match auth_session.backend.get_content_filter(viewer).await {
+                                Ok(filter) => filter.apply(&mut posts),
+                                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                            }
+                            if Thread::mark_liked(&mut posts, viewer, &auth_session.backend.db).await.is_err() {
+                                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                            }
+                            let (following, requested) = match auth_session.user {
+                                Some(u) => (
+                                    u.is_following(&name, &auth_session.backend.db).await,
+                                    u.has_requested_follow(&name, &auth_session.backend.db).await,
+                                ),
+                                None => (false, false),
+                            };
                             UserTemplate {
-                                logged_in: auth_session.user.is_some(),
-                                following: match auth_session.user {
-                                    Some(u) => u.is_following(name, &auth_session.backend.db).await,
-                                    None => false,
-                                },
+                                logged_in: viewer.is_some(),
+                                is_self: viewer == Some(u.id),
+                                following,
+                                requested,
+                                locked: !visible,
                                 user: u,
                                 posts,
                             }.into_response()
@@ -50,4 +84,240 @@ mod get {
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
     }
+
+    pub async fn tag(auth_session: AuthSession, Path(tag): Path<String>) -> impl IntoResponse {
+        let tag = normalise_tag(&tag);
+        match auth_session.backend.get_tag_posts(&tag, auth_session.user.as_ref().map(|u| u.id)).await {
+            Ok(posts) => {
+                let mut posts = match Thread::from_posts(posts, auth_session.user.as_ref().map(|u| u.id), &auth_session.backend.db).await {
+                    Ok(p) => p,
+                    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                };
+                match auth_session.backend.get_content_filter(auth_session.user.as_ref().map(|u| u.id)).await {
+                    Ok(filter) => filter.apply(&mut posts),
+                    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                }
+                if Thread::mark_liked(&mut posts, auth_session.user.as_ref().map(|u| u.id), &auth_session.backend.db).await.is_err() {
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+                TagTemplate {
+                    logged_in: auth_session.user.is_some(),
+                    following: match auth_session.user {
+                        Some(u) => u.is_following_tag(&tag, &auth_session.backend.db).await,
+                        None => false,
+                    },
+                    tag,
+                    posts,
+                }.into_response()
+            },
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn permalink(auth_session: AuthSession, messages: Messages, Path((name, id)): Path<(String, i64)>) -> impl IntoResponse {
+        let author = match auth_session.backend.get_user(&name).await {
+            Ok(Some(u)) => u,
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        let filter = match auth_session.backend.get_content_filter(auth_session.user.as_ref().map(|u| u.id)).await {
+            Ok(f) => f,
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        match auth_session.backend.get_post(id, auth_session.user.as_ref().map(|u| u.id)).await {
+            Ok(Some(post)) if post.username == author.username => match post.into(auth_session.user.as_ref().map(|u| u.id), &auth_session.backend.db).await {
+                Ok(mut post) => {
+                    filter.apply(std::slice::from_mut(&mut post));
+                    if Thread::mark_liked(std::slice::from_mut(&mut post), auth_session.user.as_ref().map(|u| u.id), &auth_session.backend.db).await.is_err() {
+                        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                    }
+                    let replies = match auth_session.backend.get_replies(id).await {
+                        Ok(r) => r,
+                        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                    };
+                    let can_reply = match &auth_session.user {
+                        Some(u) => match auth_session.backend.can_reply(u.id, id).await {
+                            Ok(c) => c,
+                            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                        },
+                        None => false,
+                    };
+                    PermalinkTemplate {
+                        messages: messages.into_iter().collect(),
+                        logged_in: auth_session.user.is_some(),
+                        is_author: auth_session.user.is_some_and(|u| u.id == author.id),
+                        post,
+                        replies,
+                        can_reply,
+                    }.into_response()
+                },
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            },
+            Ok(_) => StatusCode::NOT_FOUND.into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn notes(auth_session: AuthSession, Path((name, id)): Path<(String, i64)>, Query(Page{page}): Query<Page>) -> impl IntoResponse {
+        let page = page.unwrap_or(0).max(0);
+        let viewer = auth_session.user.as_ref().map(|u| u.id);
+        let author = match auth_session.backend.get_user(&name).await {
+            Ok(Some(u)) => u,
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        let mut post = match auth_session.backend.get_post(id, viewer).await {
+            Ok(Some(post)) if post.username == author.username => match post.into(viewer, &auth_session.backend.db).await {
+                Ok(post) => post,
+                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            },
+            Ok(_) => return StatusCode::NOT_FOUND.into_response(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        match auth_session.backend.get_content_filter(viewer).await {
+            Ok(filter) => filter.apply(std::slice::from_mut(&mut post)),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+        if Thread::mark_liked(std::slice::from_mut(&mut post), viewer, &auth_session.backend.db).await.is_err() {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        match auth_session.backend.get_notes(id, viewer, page).await {
+            Ok((notes, has_next)) => NotesTemplate {
+                post,
+                notes,
+                page,
+                has_next,
+            }.into_response(),
+            Err(e) => {
+                println!("{:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+
+    pub async fn revisions(auth_session: AuthSession, Path((name, id)): Path<(String, i64)>) -> impl IntoResponse {
+        let author = match auth_session.backend.get_user(&name).await {
+            Ok(Some(u)) => u,
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        let post = match auth_session.backend.get_post(id, auth_session.user.as_ref().map(|u| u.id)).await {
+            Ok(Some(post)) if post.username == author.username => post,
+            Ok(_) => return StatusCode::NOT_FOUND.into_response(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        let post = match post.into(auth_session.user.as_ref().map(|u| u.id), &auth_session.backend.db).await {
+            Ok(mut thread) => match thread.contents.pop() {
+                Some(p) => p,
+                None => return StatusCode::NOT_FOUND.into_response(),
+            },
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        match auth_session.backend.get_revisions(id).await {
+            Ok(revisions) => RevisionsTemplate {
+                post,
+                revisions,
+            }.into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn followers(auth_session: AuthSession, Path(name): Path<String>, Query(Page{page}): Query<Page>) -> impl IntoResponse {
+        let page = page.unwrap_or(0).max(0);
+        match auth_session.backend.get_user(&name).await {
+            Ok(Some(u)) => match auth_session.backend.get_followers(u.id, page).await {
+                Ok((users, has_next)) => FollowListTemplate {
+                    title: "Followers",
+                    user: u,
+                    users,
+                    page,
+                    has_next,
+                }.into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            },
+            Ok(None) => StatusCode::NOT_FOUND.into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn following(auth_session: AuthSession, Path(name): Path<String>, Query(Page{page}): Query<Page>) -> impl IntoResponse {
+        let page = page.unwrap_or(0).max(0);
+        match auth_session.backend.get_user(&name).await {
+            Ok(Some(u)) => match auth_session.backend.get_following(u.id, page).await {
+                Ok((users, has_next)) => FollowListTemplate {
+                    title: "Following",
+                    user: u,
+                    users,
+                    page,
+                    has_next,
+                }.into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            },
+            Ok(None) => StatusCode::NOT_FOUND.into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn likes(auth_session: AuthSession, Path(name): Path<String>, Query(Page{page}): Query<Page>) -> impl IntoResponse {
+        let page = page.unwrap_or(0).max(0);
+        let viewer = auth_session.user.as_ref().map(|u| u.id);
+        let user = match auth_session.backend.get_user(&name).await {
+            Ok(Some(u)) => u,
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        let (posts, has_next) = match auth_session.backend.get_liked_posts(user.id, viewer, page).await {
+            Ok(p) => p,
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        let mut posts = match Thread::from_posts(posts, viewer, &auth_session.backend.db).await {
+            Ok(p) => p,
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        match auth_session.backend.get_content_filter(viewer).await {
+            Ok(filter) => filter.apply(&mut posts),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+        if Thread::mark_liked(&mut posts, viewer, &auth_session.backend.db).await.is_err() {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        LikesTemplate {
+            user,
+            posts,
+            page,
+            has_next,
+        }.into_response()
+    }
+
+    pub async fn media(auth_session: AuthSession, headers: HeaderMap, Path(key): Path<String>) -> impl IntoResponse {
+        match auth_session.backend.get_media(&key, auth_session.user.as_ref().map(|u| u.id)).await {
+            Ok(Some((mime, data, public))) => {
+                // Caches have to check back every few minutes, so deleting a post or locking an
+                // account withdraws its media soon after. Keys are never reused, so they tag the contents
+                let etag = format!("\"{}\"", key);
+                let cache = [
+                    (header::ETAG, etag.clone()),
+                    // Anything short of public must not end up in a shared cache
+                    (header::CACHE_CONTROL, match public {
+                        true => "public, max-age=300, must-revalidate",
+                        false => "private, max-age=300, must-revalidate",
+                    }.to_string()),
+                ];
+                if headers.get(header::IF_NONE_MATCH).is_some_and(|tag| tag.as_bytes() == etag.as_bytes()) {
+                    return (StatusCode::NOT_MODIFIED, cache).into_response();
+                }
+                (
+                    cache,
+                    [
+                        (header::CONTENT_TYPE, mime),
+                        // The type was sniffed from the upload, browsers mustn't guess another
+                        (header::X_CONTENT_TYPE_OPTIONS, String::from("nosniff")),
+                    ],
+                    data,
+                ).into_response()
+            },
+            Ok(None) => StatusCode::NOT_FOUND.into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
 }
\ No newline at end of file
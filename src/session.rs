@@ -0,0 +1,285 @@
+use std::{fs::Metadata, io::ErrorKind, net::SocketAddr, path::Path};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+use anyhow::Result;
+use axum::{async_trait, extract::{ConnectInfo, Request, State}, http::{header::{COOKIE, USER_AGENT}, HeaderValue}, middleware::Next, response::Response};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sqlx::AnyPool;
+use tokio::io::AsyncWriteExt;
+use tower_sessions::{cookie::{time::OffsetDateTime, Cookie, CookieJar, Key}, session::{Id, Record}, session_store, ExpiredDeletion, Session, SessionStore};
+
+use crate::authentication::AuthSession;
+
+/// Name of the cookie holding the session id
+pub const SESSION_COOKIE: &str = "id";
+/// Session data key axum-login keeps the logged in user under
+pub const AUTH_DATA_KEY: &str = "axum-login.data";
+const USER_AGENT_KEY: &str = "session.user_agent";
+const IP_KEY: &str = "session.ip";
+
+/// Keeps sessions in the `sessions` table of the main database
+#[derive(Clone, Debug)]
+pub struct DbSessionStore {
+    db: AnyPool,
+}
+
+impl DbSessionStore {
+    pub fn new(db: AnyPool) -> Self {
+        Self { db }
+    }
+}
+
+/// The columns kept alongside a session's data so a user's sessions can be listed
+struct Details {
+    data: String,
+    user_id: Option<i64>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+}
+
+impl Details {
+    fn of(record: &Record) -> session_store::Result<Self> {
+        let text = |key: &str| record.data.get(key).and_then(|v| v.as_str()).map(String::from);
+        Ok(Self {
+            data: serde_json::to_string(record).map_err(|e| session_store::Error::Encode(e.to_string()))?,
+            user_id: record.data.get(AUTH_DATA_KEY).and_then(|d| d.get("user_id")).and_then(|id| id.as_i64()),
+            user_agent: text(USER_AGENT_KEY),
+            ip: text(IP_KEY),
+        })
+    }
+}
+
+fn backend_error(e: sqlx::Error) -> session_store::Error {
+    session_store::Error::Backend(e.to_string())
+}
+
+#[async_trait]
+impl SessionStore for DbSessionStore {
+    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+        // Ids are random, a collision is unlikely but would hand over someone else's session
+        loop {
+            let taken = sqlx::query("SELECT id FROM sessions WHERE id = $1")
+                .bind(record.id.to_string())
+                .fetch_optional(&self.db)
+                .await
+                .map_err(backend_error)?;
+            match taken {
+                Some(_) => record.id = Id::default(),
+                None => break,
+            }
+        }
+        let details = Details::of(record)?;
+        sqlx::query("INSERT INTO sessions (id, data, expiry_date, user_id, user_agent, ip, created, last_seen) VALUES ($1, $2, $3, $4, $5, $6, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP)")
+            .bind(record.id.to_string())
+            .bind(details.data)
+            .bind(record.expiry_date.unix_timestamp())
+            .bind(details.user_id)
+            .bind(details.user_agent)
+            .bind(details.ip)
+            .execute(&self.db)
+            .await
+            .map_err(backend_error)?;
+        Ok(())
+    }
+
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        // Only sessions loaded from the store are saved, so one revoked while a request
+        // was in flight stays revoked rather than being written back
+        let details = Details::of(record)?;
+        sqlx::query("UPDATE sessions SET data = $2, expiry_date = $3, user_id = $4, user_agent = $5, ip = $6, last_seen = CURRENT_TIMESTAMP WHERE id = $1")
+            .bind(record.id.to_string())
+            .bind(details.data)
+            .bind(record.expiry_date.unix_timestamp())
+            .bind(details.user_id)
+            .bind(details.user_agent)
+            .bind(details.ip)
+            .execute(&self.db)
+            .await
+            .map_err(backend_error)?;
+        Ok(())
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        let data: Option<(String, )> = sqlx::query_as("SELECT data FROM sessions WHERE id = $1 AND expiry_date > $2")
+            .bind(session_id.to_string())
+            .bind(OffsetDateTime::now_utc().unix_timestamp())
+            .fetch_optional(&self.db)
+            .await
+            .map_err(backend_error)?;
+        match data {
+            Some((data, )) => Ok(Some(serde_json::from_str(&data).map_err(|e| session_store::Error::Decode(e.to_string()))?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        sqlx::query("DELETE FROM sessions WHERE id = $1")
+            .bind(session_id.to_string())
+            .execute(&self.db)
+            .await
+            .map_err(backend_error)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ExpiredDeletion for DbSessionStore {
+    async fn delete_expired(&self) -> session_store::Result<()> {
+        sqlx::query("DELETE FROM sessions WHERE expiry_date <= $1")
+            .bind(OffsetDateTime::now_utc().unix_timestamp())
+            .execute(&self.db)
+            .await
+            .map_err(backend_error)?;
+        Ok(())
+    }
+}
+
+/// Cookie signing keys, the first signs new cookies and the rest are only accepted,
+/// so that a key can be rotated without logging everyone out
+#[derive(Clone)]
+pub struct SigningKeys {
+    pub current: Key,
+    pub previous: Vec<Key>,
+}
+
+impl std::fmt::Debug for SigningKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKeys")
+            .field("previous", &self.previous.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SigningKeys {
+    /// Uses the base64 encoded `keys` if any are configured, otherwise reads them one per line
+    /// from `key_file`, which is created with a fresh key if it doesn't exist yet
+    pub async fn load(keys: &[String], key_file: &str) -> Result<Self> {
+        let encoded = match keys.is_empty() {
+            true => read_key_file(key_file).await?,
+            false => keys.to_vec(),
+        };
+        let mut keys = Vec::new();
+        for key in encoded {
+            let bytes = STANDARD.decode(key.trim())?;
+            keys.push(Key::try_from(bytes.as_slice()).map_err(|_| anyhow::anyhow!("Session keys must be at least 64 bytes"))?);
+        }
+        let mut keys = keys.into_iter();
+        match keys.next() {
+            Some(current) => Ok(Self { current, previous: keys.collect() }),
+            None => Err(anyhow::anyhow!("No session signing key configured")),
+        }
+    }
+
+    /// The session id in a cookie signed with a previous key, signed again with the current one
+    fn resign(&self, value: &str) -> Option<String> {
+        let mut jar = CookieJar::new();
+        jar.add_original(Cookie::new(SESSION_COOKIE, value.to_string()));
+        if jar.signed(&self.current).get(SESSION_COOKIE).is_some() {
+            return None;
+        }
+        let id = self.previous.iter().find_map(|key| jar.signed(key).get(SESSION_COOKIE))?;
+        let mut jar = CookieJar::new();
+        jar.signed_mut(&self.current).add(id);
+        jar.get(SESSION_COOKIE).map(|c| c.value().to_string())
+    }
+}
+
+async fn read_key_file(path: &str) -> Result<Vec<String>> {
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) => {
+            if accessible_by_others(&metadata) {
+                return Err(anyhow::anyhow!("{} can be accessed by other users, restrict it with `chmod 600 {}`", path, path));
+            }
+            let contents = tokio::fs::read_to_string(path).await?;
+            Ok(contents.lines().filter(|l| !l.trim().is_empty()).map(String::from).collect())
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let key = STANDARD.encode(Key::generate().master());
+            if let Some(dir) = Path::new(path).parent().filter(|d| !d.as_os_str().is_empty()) {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            let mut options = tokio::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            let mut file = options.open(path).await?;
+            file.write_all(format!("{}\n", key).as_bytes()).await?;
+            file.sync_all().await?;
+            Ok(vec![key])
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether users other than the owner can read or change the file
+#[cfg(unix)]
+fn accessible_by_others(metadata: &Metadata) -> bool {
+    metadata.permissions().mode() & 0o077 != 0
+}
+
+#[cfg(not(unix))]
+fn accessible_by_others(_: &Metadata) -> bool {
+    false
+}
+
+/// Marks a request whose session cookie was signed again with the current key
+#[derive(Clone, Copy)]
+struct Resigned;
+
+/// Swaps a session cookie signed with a previous key for one signed with the current key
+/// before the session layer sees it, and marks the request so [`resave_resigned`] can
+/// have the re-signed cookie sent back
+pub async fn rotate_keys(State(keys): State<SigningKeys>, mut request: Request, next: Next) -> Response {
+    if !keys.previous.is_empty() {
+        let header = request.headers().get(COOKIE).and_then(|h| h.to_str().ok()).map(String::from);
+        if let Some(header) = header {
+            let mut rotated = false;
+            let cookies: Vec<String> = Cookie::split_parse(header.as_str()).filter_map(|c| c.ok()).map(|c| {
+                match c.name() == SESSION_COOKIE {
+                    true => match keys.resign(c.value()) {
+                        Some(value) => {
+                            rotated = true;
+                            format!("{}={}", SESSION_COOKIE, value)
+                        },
+                        None => c.to_string(),
+                    },
+                    false => c.to_string(),
+                }
+            }).collect();
+            if rotated {
+                if let Ok(value) = HeaderValue::from_str(&cookies.join("; ")) {
+                    request.headers_mut().insert(COOKIE, value);
+                    request.extensions_mut().insert(Resigned);
+                }
+            }
+        }
+    }
+    next.run(request).await
+}
+
+/// The session layer only sends a cookie for a modified session, so one whose cookie was
+/// re-signed is marked modified to have it saved and the new cookie reach the browser
+pub async fn resave_resigned(session: Session, request: Request, next: Next) -> Response {
+    if request.extensions().get::<Resigned>().is_some() {
+        session.set_expiry(session.expiry());
+    }
+    next.run(request).await
+}
+
+/// Remembers the user agent and address a logged in session was last used from,
+/// for listing on the sessions page
+pub async fn track_session(auth_session: AuthSession, session: Session, ConnectInfo(addr): ConnectInfo<SocketAddr>, request: Request, next: Next) -> Response {
+    if auth_session.user.is_some() {
+        let user_agent = request.headers().get(USER_AGENT).and_then(|h| h.to_str().ok()).unwrap_or_default().to_string();
+        let updates = [(USER_AGENT_KEY, user_agent), (IP_KEY, addr.ip().to_string())];
+        for (key, value) in updates {
+            if session.get::<String>(key).await.ok().flatten().as_ref() != Some(&value) {
+                if let Err(e) = session.insert(key, value).await {
+                    println!("{:?}", e);
+                }
+            }
+        }
+    }
+    next.run(request).await
+}
@@ -1,7 +1,8 @@
 use askama::Template;
 use axum_messages::Message;
 
-use crate::model::{DisplayUser, Thread};
+use crate::model::{ActiveSession, DisplayUser, Note, Notification, NotificationKind, Passkey, Post, Reply, Revision, Thread};
+use crate::two_factor::Enrolment;
 
 #[derive(Template)]
 #[template(path = "home.html")]
@@ -27,6 +28,31 @@ pub struct DashTemplate {
     pub messages: Vec<Message>,
     pub user: DisplayUser,
     pub posts: Vec<Thread>,
+    pub next: Option<i64>,
+    pub unread: i64,
+    /// Only the newest page follows along as posts arrive
+    pub live: bool,
+    /// Whether the account's email address has been verified, which posting waits on
+    pub verified: bool,
+}
+
+#[derive(Template)]
+#[template(path = "forgot_password.html")]
+pub struct ForgotPasswordTemplate {
+    pub messages: Vec<Message>,
+}
+
+#[derive(Template)]
+#[template(path = "reset_password.html")]
+pub struct ResetPasswordTemplate {
+    pub messages: Vec<Message>,
+    pub token: String,
+}
+
+#[derive(Template)]
+#[template(path = "two_factor_login.html")]
+pub struct TwoFactorLoginTemplate {
+    pub messages: Vec<Message>,
 }
 
 #[derive(Template)]
@@ -34,13 +60,145 @@ pub struct DashTemplate {
 pub struct PostTemplate {
     pub messages: Vec<Message>,
     pub user: DisplayUser,
+    pub reblog: Option<Thread>,
+    pub editing: bool,
+    pub summary: String,
+    pub body: String,
+    pub format: String,
+    pub tags: String,
+    pub replies: String,
 }
 
 #[derive(Template)]
 #[template(path = "user.html")]
 pub struct UserTemplate {
+    pub logged_in: bool,
+    pub is_self: bool,
+    pub following: bool,
+    pub requested: bool,
+    pub locked: bool,
+    pub user: DisplayUser,
+    pub posts: Vec<Thread>,
+}
+
+#[derive(Template)]
+#[template(path = "permalink.html")]
+pub struct PermalinkTemplate {
+    pub messages: Vec<Message>,
+    pub logged_in: bool,
+    pub is_author: bool,
+    pub post: Thread,
+    pub replies: Vec<Reply>,
+    pub can_reply: bool,
+}
+
+#[derive(Template)]
+#[template(path = "revisions.html")]
+pub struct RevisionsTemplate {
+    pub post: Post,
+    pub revisions: Vec<Revision>,
+}
+
+#[derive(Template)]
+#[template(path = "tag.html")]
+pub struct TagTemplate {
     pub logged_in: bool,
     pub following: bool,
+    pub tag: String,
+    pub posts: Vec<Thread>,
+}
+
+#[derive(Template)]
+#[template(path = "follow_requests.html")]
+pub struct FollowRequestsTemplate {
+    pub messages: Vec<Message>,
+    pub requests: Vec<DisplayUser>,
+}
+
+#[derive(Template)]
+#[template(path = "settings.html")]
+pub struct SettingsTemplate {
+    pub messages: Vec<Message>,
+    pub approve_followers: bool,
+    pub cw_expand: bool,
+    pub cw_hidden_words: String,
+    pub notification_kinds: [NotificationKind; 6],
+    pub muted: Vec<NotificationKind>,
+}
+
+#[derive(Template)]
+#[template(path = "passkeys.html")]
+pub struct PasskeysTemplate {
+    pub messages: Vec<Message>,
+    pub passkeys: Vec<Passkey>,
+}
+
+#[derive(Template)]
+#[template(path = "two_factor.html")]
+pub struct TwoFactorTemplate {
+    pub messages: Vec<Message>,
+    pub enabled: bool,
+    pub recovery_codes: i64,
+    /// Set while enrolling
+    pub enrolment: Option<Enrolment>,
+}
+
+#[derive(Template)]
+#[template(path = "recovery_codes.html")]
+pub struct RecoveryCodesTemplate {
+    pub codes: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "sessions.html")]
+pub struct SessionsTemplate {
+    pub messages: Vec<Message>,
+    pub sessions: Vec<ActiveSession>,
+    /// Id of the session viewing the page
+    pub current: String,
+    /// Logging out doesn't stop these from logging in again
+    pub passkeys: Vec<Passkey>,
+}
+
+#[derive(Template)]
+#[template(path = "follow_list.html")]
+pub struct FollowListTemplate {
+    pub title: &'static str,
+    pub user: DisplayUser,
+    pub users: Vec<DisplayUser>,
+    pub page: i64,
+    pub has_next: bool,
+}
+
+#[derive(Template)]
+#[template(path = "likes.html")]
+pub struct LikesTemplate {
     pub user: DisplayUser,
     pub posts: Vec<Thread>,
-}
\ No newline at end of file
+    pub page: i64,
+    pub has_next: bool,
+}
+
+#[derive(Template)]
+#[template(path = "notes.html")]
+pub struct NotesTemplate {
+    pub post: Thread,
+    pub notes: Vec<Note>,
+    pub page: i64,
+    pub has_next: bool,
+}
+
+#[derive(Template)]
+#[template(path = "notifications.html")]
+pub struct NotificationsTemplate {
+    pub notifications: Vec<Notification>,
+    pub page: i64,
+    pub has_next: bool,
+}
+
+/// A single thread on its own, for pushing to live streams
+#[derive(Template)]
+#[template(path = "post_fragment.html")]
+pub struct ThreadTemplate {
+    pub post: Thread,
+}
@@ -0,0 +1,83 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tower_sessions::cookie::{time::{Duration, OffsetDateTime}, Key};
+
+use crate::session::SigningKeys;
+
+/// What a token allows, so one can't be used in place of another
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Purpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl Purpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Purpose::VerifyEmail => "verify_email",
+            Purpose::ResetPassword => "reset_password",
+        }
+    }
+
+    /// How long a token stays valid after being issued
+    pub fn lifetime(&self) -> Duration {
+        match self {
+            Purpose::VerifyEmail => Duration::days(2),
+            Purpose::ResetPassword => Duration::hours(1),
+        }
+    }
+}
+
+/// Issues a signed token for `user_id` that expires after the purpose's lifetime.
+/// `state` isn't included in the token but is part of the signature, so the token stops
+/// working once it changes (e.g. the email address being verified or the password hash)
+pub fn issue(keys: &SigningKeys, purpose: Purpose, user_id: i64, state: &str) -> String {
+    let expires = (OffsetDateTime::now_utc() + purpose.lifetime()).unix_timestamp();
+    let payload = format!("{}.{}.{}", purpose.as_str(), user_id, expires);
+    let signature = sign(&keys.current, &payload, state).finalize().into_bytes();
+    format!("{}.{}", URL_SAFE_NO_PAD.encode(payload), URL_SAFE_NO_PAD.encode(signature))
+}
+
+/// The user a token claims to be for, which can't be trusted until it's passed `verify`
+pub fn user_id(token: &str) -> Option<i64> {
+    let (payload, _) = open(token)?;
+    payload.split('.').nth(1)?.parse().ok()
+}
+
+/// Whether a token was issued by us for this purpose and state, and hasn't expired
+pub fn verify(keys: &SigningKeys, purpose: Purpose, token: &str, state: &str) -> bool {
+    let (payload, signature) = match open(token) {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let signed = std::iter::once(&keys.current).chain(&keys.previous)
+        .any(|key| sign(key, &payload, state).verify_slice(&signature).is_ok());
+    let fields: Vec<&str> = payload.split('.').collect();
+    let expires: Option<i64> = fields.get(2).and_then(|e| e.parse().ok());
+    signed
+        && fields.first() == Some(&purpose.as_str())
+        && expires.is_some_and(|e| e > OffsetDateTime::now_utc().unix_timestamp())
+}
+
+fn open(token: &str) -> Option<(String, Vec<u8>)> {
+    let (payload, signature) = token.split_once('.')?;
+    let payload = String::from_utf8(URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    Some((payload, URL_SAFE_NO_PAD.decode(signature).ok()?))
+}
+
+/// Tokens are signed with a key derived from the session key rather than the key cookies
+/// are signed with, so neither can be made to pass for the other
+fn token_key(key: &Key) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.master()).expect("HMAC takes keys of any length");
+    mac.update(b"email-token");
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn sign(key: &Key, payload: &str, state: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&token_key(key)).expect("HMAC takes keys of any length");
+    mac.update(payload.as_bytes());
+    mac.update(b"\n");
+    mac.update(state.as_bytes());
+    mac
+}
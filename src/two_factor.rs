@@ -0,0 +1,125 @@
+use std::fmt::Write;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use qrcodegen::{QrCode, QrCodeEcc};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, Secret, TOTP};
+use tower_sessions::cookie::time::{Duration, OffsetDateTime};
+
+const ISSUER: &str = "Cotyledon";
+const STEP: u64 = 30;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+/// Session data key for a login waiting on its second step
+pub const PENDING_LOGIN_KEY: &str = "two_factor.pending";
+/// How long the second step can take before the password has to be entered again
+const PENDING_LOGIN_LIFETIME: Duration = Duration::minutes(5);
+/// Wrong codes allowed for an account before it stops accepting any for a while
+pub const MAX_FAILURES: i64 = 5;
+/// How long an account stops accepting codes after too many wrong ones
+pub const LOCKOUT: Duration = Duration::minutes(15);
+
+/// The outcome of checking the second step of a login
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verification {
+    Accepted,
+    Rejected,
+    /// Too many wrong codes were entered recently, so this one wasn't checked
+    LockedOut,
+}
+
+/// A login whose password was right, kept in the session until a code is entered
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PendingLogin {
+    pub user_id: i64,
+    pub next: Option<String>,
+    pub expires: i64,
+}
+
+impl PendingLogin {
+    pub fn new(user_id: i64, next: Option<String>) -> Self {
+        Self {
+            user_id,
+            next,
+            expires: (OffsetDateTime::now_utc() + PENDING_LOGIN_LIFETIME).unix_timestamp(),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires <= OffsetDateTime::now_utc().unix_timestamp()
+    }
+}
+
+/// What an authenticator app needs to be set up, shown while enrolling
+pub struct Enrolment {
+    pub secret: String,
+    /// The `otpauth://` provisioning URI
+    pub uri: String,
+    /// The URI as a QR code, an inline SVG
+    pub qr: String,
+}
+
+impl Enrolment {
+    pub fn new(secret: &str, username: &str) -> Option<Self> {
+        let uri = authenticator(secret, username)?.get_url();
+        Some(Self {
+            secret: secret.to_string(),
+            qr: qr_svg(&uri)?,
+            uri,
+        })
+    }
+}
+
+/// A fresh base32 encoded secret, 160 bits as RFC 4226 recommends
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+    Secret::Raw(bytes.to_vec()).to_encoded().to_string()
+}
+
+fn authenticator(secret: &str, username: &str) -> Option<TOTP> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+    // No skew, `accepted_step` checks the neighbouring steps itself to know which one matched.
+    // The label is `issuer:account`, so a colon can't be part of the account name
+    TOTP::new(Algorithm::SHA1, 6, 0, STEP, secret, Some(ISSUER.to_string()), username.replace(':', "")).ok()
+}
+
+/// The time step `code` is valid for, allowing for a step of clock drift either way
+pub fn accepted_step(secret: &str, code: &str) -> Option<i64> {
+    let totp = authenticator(secret, "")?;
+    let now = OffsetDateTime::now_utc().unix_timestamp() as u64 / STEP;
+    (now - 1..=now + 1).find(|step| totp.check(code, step * STEP)).map(|step| step as i64)
+}
+
+/// Codes look like `abcde-fghij`, in lowercase so they're easy to read back
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT).map(|_| {
+        let code: String = OsRng.sample_iter(&Alphanumeric).take(10).map(|c| char::from(c).to_ascii_lowercase()).collect();
+        format!("{}-{}", &code[..5], &code[5..])
+    }).collect()
+}
+
+/// Recovery codes are random enough that a fast hash is fine, which lets them be looked up by it.
+/// Case, spaces and dashes are ignored
+pub fn hash_recovery_code(code: &str) -> String {
+    let code: String = code.chars().filter(|c| c.is_ascii_alphanumeric()).map(|c| c.to_ascii_lowercase()).collect();
+    STANDARD.encode(Sha256::digest(code.as_bytes()))
+}
+
+fn qr_svg(text: &str) -> Option<String> {
+    let qr = QrCode::encode_text(text, QrCodeEcc::Medium).ok()?;
+    let border = 4;
+    let mut path = String::new();
+    for y in 0..qr.size() {
+        for x in 0..qr.size() {
+            if qr.get_module(x, y) {
+                let _ = write!(path, "M{},{}h1v1h-1z", x + border, y + border);
+            }
+        }
+    }
+    let size = qr.size() + border * 2;
+    Some(format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {size} {size}" width="200" height="200" shape-rendering="crispEdges"><rect width="100%" height="100%" fill="#fff"/><path d="{path}" fill="#000"/></svg>"##
+    ))
+}
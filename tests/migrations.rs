@@ -0,0 +1,69 @@
+//! Migrations that rewrite existing data, run against rows written by the schema before them
+
+use sqlx::{migrate::Migrator, Connection, SqliteConnection};
+
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// A database with every migration before `version` applied
+async fn database_before(version: i64) -> SqliteConnection {
+    let mut db = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+    for migration in MIGRATOR.iter().filter(|m| m.version < version) {
+        sqlx::raw_sql(&migration.sql).execute(&mut db).await.unwrap();
+    }
+    db
+}
+
+/// Applies the migrations from `version` on
+async fn migrate_from(db: &mut SqliteConnection, version: i64) {
+    for migration in MIGRATOR.iter().filter(|m| m.version >= version) {
+        sqlx::raw_sql(&migration.sql).execute(&mut *db).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn thread_strings_become_ancestry() {
+    let mut db = database_before(4).await;
+    // Thread strings list a post's ancestors, root first
+    let threads = [
+        (1, None),
+        (2, Some("1")),
+        (3, Some("1/2")),
+        (4, Some("1/2/3")),
+        (5, Some("1/999")),
+        (6, Some("1/x")),
+        (7, Some("")),
+        (8, Some("x")),
+    ];
+    for (id, thread) in threads {
+        sqlx::query("INSERT INTO posts (id, user_id, thread, body) VALUES ($1, 1, $2, 'body')")
+            .bind(id)
+            .bind(thread)
+            .execute(&mut db)
+            .await
+            .unwrap();
+    }
+    migrate_from(&mut db, 4).await;
+
+    let ancestry: Vec<(i64, i64, i64)> = sqlx::query_as("SELECT post_id, ancestor_id, depth FROM post_ancestry ORDER BY post_id, depth")
+        .fetch_all(&mut db)
+        .await
+        .unwrap();
+    assert_eq!(ancestry, vec![
+        (2, 1, 1),
+        (3, 2, 1),
+        (3, 1, 2),
+        (4, 3, 1),
+        (4, 2, 2),
+        (4, 1, 3),
+        // Missing and non-numeric ids are dropped, the ancestors that do exist keep their distance
+        (5, 1, 2),
+        (6, 1, 2),
+    ]);
+    let posts: (i64, ) = sqlx::query_as("SELECT COUNT(*) FROM posts").fetch_one(&mut db).await.unwrap();
+    assert_eq!(posts.0, threads.len() as i64);
+    let thread_column: Option<(String, )> = sqlx::query_as("SELECT name FROM pragma_table_info('posts') WHERE name = 'thread'")
+        .fetch_optional(&mut db)
+        .await
+        .unwrap();
+    assert!(thread_column.is_none());
+}
@@ -0,0 +1,346 @@
+//! Adding a passkey and logging in with it, through the same routes the browser uses,
+//! with a software authenticator standing in for the browser's
+
+use std::net::SocketAddr;
+
+use axum::{body::{to_bytes, Body}, extract::ConnectInfo, http::{header, Request, StatusCode}, Router};
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine};
+use ciborium::Value;
+use cotyledon::{app::App, config::Config};
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use rand::{rngs::OsRng, RngCore};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{Connection, SqliteConnection};
+use tempfile::TempDir;
+use tower::ServiceExt;
+
+const ORIGIN: &str = "http://localhost:3000";
+const RP_ID: &str = "localhost";
+const PASSWORD: &str = "correct horse battery staple";
+
+/// The site on a fresh database, kept in a directory that's removed once it's dropped
+struct TestApp {
+    router: Router,
+    dir: TempDir,
+}
+
+impl TestApp {
+    async fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let mut key = [0u8; 64];
+        OsRng.fill_bytes(&mut key);
+        let config = Config {
+            database_url: format!("sqlite:{}?mode=rwc", dir.path().join("test.db").display()),
+            media_path: dir.path().join("media").display().to_string(),
+            session_keys: vec![STANDARD.encode(key)],
+            base_url: String::from(ORIGIN),
+            ..Config::default()
+        };
+        let (router, _) = App::new(config).await.unwrap().router().await.unwrap();
+        Self { router, dir }
+    }
+
+    fn client(&self) -> Client {
+        Client { router: self.router.clone(), cookie: None }
+    }
+
+    /// Ages the challenges every session is waiting on past their expiry
+    async fn expire_challenges(&self) {
+        let mut db = SqliteConnection::connect(&format!("sqlite:{}", self.dir.path().join("test.db").display())).await.unwrap();
+        for key in ["passkey.registration", "passkey.authentication"] {
+            sqlx::query("UPDATE sessions SET data = json_set(data, '$.data.\"' || $1 || '\".expires', 0) WHERE json_extract(data, '$.data.\"' || $1 || '\"') IS NOT NULL")
+                .bind(key)
+                .execute(&mut db)
+                .await
+                .unwrap();
+        }
+    }
+}
+
+/// A browser, keeping hold of its session cookie between requests
+struct Client {
+    router: Router,
+    cookie: Option<String>,
+}
+
+impl Client {
+    async fn send(&mut self, method: &str, uri: &str, content_type: &str, body: String) -> (StatusCode, serde_json::Value) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, content_type);
+        if let Some(cookie) = &self.cookie {
+            request = request.header(header::COOKIE, cookie);
+        }
+        let mut request = request.body(Body::from(body)).unwrap();
+        request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+        let response = self.router.clone().oneshot(request).await.unwrap();
+        if let Some(cookie) = response.headers().get(header::SET_COOKIE) {
+            self.cookie = cookie.to_str().unwrap().split(';').next().map(String::from);
+        }
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    async fn form(&mut self, uri: &str, body: &str) -> StatusCode {
+        self.send("POST", uri, "application/x-www-form-urlencoded", body.to_string()).await.0
+    }
+
+    async fn json(&mut self, uri: &str, body: serde_json::Value) -> (StatusCode, serde_json::Value) {
+        self.send("POST", uri, "application/json", body.to_string()).await
+    }
+
+    async fn logged_in(&mut self) -> bool {
+        self.send("GET", "/dash", "text/plain", String::new()).await.0 == StatusCode::OK
+    }
+
+    /// A logged in client for a new account
+    async fn register(app: &TestApp, username: &str) -> Self {
+        let mut client = app.client();
+        let status = client.form("/register", &format!("email={}%40example.com&username={}&password={}", username, username, urlencoding::encode(PASSWORD))).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        client
+    }
+}
+
+/// A passkey provider in software, holding one ES256 passkey
+struct Authenticator {
+    key: SigningKey,
+    id: Vec<u8>,
+    sign_count: u32,
+    /// The user handle the passkey was created with, given back when logging in
+    user_handle: Option<String>,
+}
+
+impl Authenticator {
+    fn new() -> Self {
+        let mut id = vec![0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        Self { key: SigningKey::random(&mut OsRng), id, sign_count: 0, user_handle: None }
+    }
+
+    /// Answers `navigator.credentials.create` options the way a browser would
+    fn create(&mut self, options: &serde_json::Value, origin: &str) -> serde_json::Value {
+        self.user_handle = options["user"]["id"].as_str().map(String::from);
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let cose_key = Value::Map(vec![
+            (Value::Integer(1.into()), Value::Integer(2.into())),
+            (Value::Integer(3.into()), Value::Integer((-7).into())),
+            (Value::Integer((-1).into()), Value::Integer(1.into())),
+            (Value::Integer((-2).into()), Value::Bytes(point.x().unwrap().to_vec())),
+            (Value::Integer((-3).into()), Value::Bytes(point.y().unwrap().to_vec())),
+        ]);
+        let mut attested = vec![0u8; 16];
+        attested.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+        attested.extend_from_slice(&self.id);
+        ciborium::into_writer(&cose_key, &mut attested).unwrap();
+        let rp_id = options["rp"]["id"].as_str().unwrap();
+        let attestation = Value::Map(vec![
+            (Value::Text(String::from("fmt")), Value::Text(String::from("none"))),
+            (Value::Text(String::from("attStmt")), Value::Map(Vec::new())),
+            (Value::Text(String::from("authData")), Value::Bytes(authenticator_data(rp_id, 0x45, self.sign_count, &attested))),
+        ]);
+        let mut attestation_object = Vec::new();
+        ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+        json!({
+            "id": URL_SAFE_NO_PAD.encode(&self.id),
+            "client_data": URL_SAFE_NO_PAD.encode(client_data("webauthn.create", &options["challenge"], origin)),
+            "attestation_object": URL_SAFE_NO_PAD.encode(attestation_object),
+            "name": "Software",
+            "password": PASSWORD,
+        })
+    }
+
+    /// Answers `navigator.credentials.get` options the way a browser would
+    fn get(&mut self, options: &serde_json::Value, origin: &str, rp_id: &str) -> serde_json::Value {
+        self.sign_count += 1;
+        let auth_data = authenticator_data(rp_id, 0x05, self.sign_count, &[]);
+        let client_data = client_data("webauthn.get", &options["challenge"], origin);
+        let mut signed = auth_data.clone();
+        signed.extend_from_slice(&Sha256::digest(&client_data));
+        let signature: Signature = self.key.sign(&signed);
+        json!({
+            "id": URL_SAFE_NO_PAD.encode(&self.id),
+            "client_data": URL_SAFE_NO_PAD.encode(client_data),
+            "authenticator_data": URL_SAFE_NO_PAD.encode(auth_data),
+            "signature": URL_SAFE_NO_PAD.encode(signature.to_der().as_bytes()),
+            "user_handle": self.user_handle,
+        })
+    }
+}
+
+fn client_data(kind: &str, challenge: &serde_json::Value, origin: &str) -> Vec<u8> {
+    json!({ "type": kind, "challenge": challenge, "origin": origin }).to_string().into_bytes()
+}
+
+/// Flags 0x05 are user present and verified, 0x45 adds attested credential data
+fn authenticator_data(rp_id: &str, flags: u8, sign_count: u32, rest: &[u8]) -> Vec<u8> {
+    let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+    data.push(flags);
+    data.extend_from_slice(&sign_count.to_be_bytes());
+    data.extend_from_slice(rest);
+    data
+}
+
+/// Adds a passkey for `client`'s account
+async fn add_passkey(client: &mut Client) -> Authenticator {
+    let (status, options) = client.json("/settings/passkeys/options", json!({ "password": PASSWORD })).await;
+    assert_eq!(status, StatusCode::OK);
+    let mut authenticator = Authenticator::new();
+    let (status, _) = client.json("/settings/passkeys", authenticator.create(&options, ORIGIN)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    authenticator
+}
+
+async fn login_options(client: &mut Client) -> serde_json::Value {
+    let (status, options) = client.json("/login/passkey/options", json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(options["rpId"], RP_ID);
+    options
+}
+
+#[tokio::test]
+async fn add_passkey_and_log_in() {
+    let app = TestApp::new().await;
+    let mut alice = Client::register(&app, "alice").await;
+    let mut authenticator = add_passkey(&mut alice).await;
+
+    let mut browser = app.client();
+    assert!(!browser.logged_in().await);
+    let options = login_options(&mut browser).await;
+    let (status, body) = browser.json("/login/passkey", authenticator.get(&options, ORIGIN, RP_ID)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["redirect"], "/dash");
+    assert!(browser.logged_in().await);
+
+    // Each login goes on from the counter the last one left
+    let mut browser = app.client();
+    let options = login_options(&mut browser).await;
+    let (status, _) = browser.json("/login/passkey", authenticator.get(&options, ORIGIN, RP_ID)).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn adding_a_passkey_needs_the_password() {
+    let app = TestApp::new().await;
+    let mut alice = Client::register(&app, "alice").await;
+    let (status, _) = alice.json("/settings/passkeys/options", json!({ "password": "wrong" })).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (_, options) = alice.json("/settings/passkeys/options", json!({ "password": PASSWORD })).await;
+    let mut registration = Authenticator::new().create(&options, ORIGIN);
+    registration["password"] = json!("wrong");
+    let (status, _) = alice.json("/settings/passkeys", registration).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn adding_a_passkey_checks_origin_and_challenge() {
+    let app = TestApp::new().await;
+    let mut alice = Client::register(&app, "alice").await;
+
+    let (_, options) = alice.json("/settings/passkeys/options", json!({ "password": PASSWORD })).await;
+    let (status, _) = alice.json("/settings/passkeys", Authenticator::new().create(&options, "http://evil.example")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (_, mut options) = alice.json("/settings/passkeys/options", json!({ "password": PASSWORD })).await;
+    options["rp"]["id"] = json!("evil.example");
+    let (status, _) = alice.json("/settings/passkeys", Authenticator::new().create(&options, ORIGIN)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (_, options) = alice.json("/settings/passkeys/options", json!({ "password": PASSWORD })).await;
+    app.expire_challenges().await;
+    let (status, _) = alice.json("/settings/passkeys", Authenticator::new().create(&options, ORIGIN)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn login_checks_origin_and_rp_id() {
+    let app = TestApp::new().await;
+    let mut alice = Client::register(&app, "alice").await;
+    let mut authenticator = add_passkey(&mut alice).await;
+
+    let mut browser = app.client();
+    let options = login_options(&mut browser).await;
+    let (status, _) = browser.json("/login/passkey", authenticator.get(&options, "http://evil.example", RP_ID)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let options = login_options(&mut browser).await;
+    let (status, _) = browser.json("/login/passkey", authenticator.get(&options, ORIGIN, "evil.example")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(!browser.logged_in().await);
+}
+
+#[tokio::test]
+async fn login_challenge_is_used_once() {
+    let app = TestApp::new().await;
+    let mut alice = Client::register(&app, "alice").await;
+    let mut authenticator = add_passkey(&mut alice).await;
+
+    let mut browser = app.client();
+    let options = login_options(&mut browser).await;
+    let assertion = authenticator.get(&options, ORIGIN, RP_ID);
+    let (status, _) = browser.json("/login/passkey", assertion.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Replayed on a session with no challenge left, and on one with a new challenge
+    let mut replay = app.client();
+    let (status, _) = replay.json("/login/passkey", assertion.clone()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    login_options(&mut replay).await;
+    let (status, _) = replay.json("/login/passkey", assertion).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(!replay.logged_in().await);
+}
+
+#[tokio::test]
+async fn login_challenge_expires() {
+    let app = TestApp::new().await;
+    let mut alice = Client::register(&app, "alice").await;
+    let mut authenticator = add_passkey(&mut alice).await;
+
+    let mut browser = app.client();
+    let options = login_options(&mut browser).await;
+    app.expire_challenges().await;
+    let (status, _) = browser.json("/login/passkey", authenticator.get(&options, ORIGIN, RP_ID)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(!browser.logged_in().await);
+}
+
+#[tokio::test]
+async fn login_counter_has_to_go_up() {
+    let app = TestApp::new().await;
+    let mut alice = Client::register(&app, "alice").await;
+    let mut authenticator = add_passkey(&mut alice).await;
+
+    let mut browser = app.client();
+    let options = login_options(&mut browser).await;
+    let (status, _) = browser.json("/login/passkey", authenticator.get(&options, ORIGIN, RP_ID)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // A clone of the passkey would carry on from the counter it was copied at
+    authenticator.sign_count -= 1;
+    let mut clone = app.client();
+    let options = login_options(&mut clone).await;
+    let (status, _) = clone.json("/login/passkey", authenticator.get(&options, ORIGIN, RP_ID)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(!clone.logged_in().await);
+}
+
+#[tokio::test]
+async fn login_checks_user_handle() {
+    let app = TestApp::new().await;
+    let mut alice = Client::register(&app, "alice").await;
+    let mut authenticator = add_passkey(&mut alice).await;
+    let mut bob = Client::register(&app, "bob").await;
+    let bobs = add_passkey(&mut bob).await;
+
+    authenticator.user_handle = bobs.user_handle.clone();
+    let mut browser = app.client();
+    let options = login_options(&mut browser).await;
+    let (status, _) = browser.json("/login/passkey", authenticator.get(&options, ORIGIN, RP_ID)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(!browser.logged_in().await);
+}